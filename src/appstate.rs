@@ -1,16 +1,70 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
-use enigo::{Enigo, KeyboardControllable};
+use crate::midi::MidiNote;
+use crate::notemappings::{
+    HoldLimit, KbdKey, NoteMapping, NoteMappings, OctaveKeys, Settle, Timing,
+};
+use crate::output::{EnigoBackend, OutputBackend};
+use crate::recorder::Recorder;
+use crate::scheduler::Scheduler;
 
-thread_local!(static ENIGO: RefCell<Enigo> = RefCell::new(Default::default()));
+/// Where the game is in its octaves, for profiles that change octave with
+/// keys rather than held modifiers
+struct OctaveState {
+    keys: OctaveKeys,
+    current: i8,
 
-use crate::notemappings::{KbdKey, NoteMappings};
+    /// The octave key that is partway through being tapped
+    pressed: Option<KbdKey>,
+}
+
+/// What `KeyGen::octave_step` did
+#[derive(Debug, PartialEq)]
+pub enum OctaveStep {
+    /// The game is already in the wanted octave
+    Done,
+    /// An octave key went down, and should be held briefly
+    Pressed,
+    /// An octave key came up, and the game needs this long to settle, if
+    /// the octave keys set their own time
+    Released(Option<u64>),
+}
 
-#[derive(Default)]
 pub struct KeyGen {
     key_state: HashMap<KbdKey, bool>,
+
+    /// The MIDI channels that each held key was pressed for, as a bitmask
+    channels: HashMap<KbdKey, u16>,
+
+    /// When each held key was pressed, and when the watchdog should release
+    /// it if it's still held
+    deadlines: HashMap<KbdKey, (Instant, Instant)>,
+
+    /// The hold limit for mappings that don't set their own
+    max_hold: Option<Duration>,
+
+    /// The modifiers that NoteMod switches on and off
+    state_modifiers: Vec<KbdKey>,
+
+    /// The keys that change the game's octave, and the octave it's in
+    octave: Option<OctaveState>,
+
+    /// Settle times from the profile
+    timing: Timing,
+
+    /// Settle times from the command line, which beat all others
+    timing_override: Timing,
+
+    backend: Box<dyn OutputBackend>,
+}
+
+impl Default for KeyGen {
+    fn default() -> KeyGen {
+        KeyGen::with_backend(Box::new(EnigoBackend::new()))
+    }
 }
 
 impl KeyGen {
@@ -18,16 +72,128 @@ impl KeyGen {
         KeyGen::default()
     }
 
-    /// Press a given key.
+    /// Create a KeyGen that sends its keys somewhere other than enigo
+    pub fn with_backend(backend: Box<dyn OutputBackend>) -> KeyGen {
+        KeyGen {
+            key_state: HashMap::new(),
+            channels: HashMap::new(),
+            deadlines: HashMap::new(),
+            max_hold: None,
+            state_modifiers: vec![KbdKey::Shift, KbdKey::Control],
+            octave: None,
+            timing: Timing::new(),
+            timing_override: Timing::new(),
+            backend,
+        }
+    }
+
+    /// The backend that keys are sent to
+    pub fn backend(&mut self) -> &mut dyn OutputBackend {
+        &mut *self.backend
+    }
+
+    /// Release keys automatically once they have been held for longer than
+    /// `max_hold`, unless their mapping says otherwise.  `None` disables
+    /// the watchdog.
+    pub fn set_max_hold(&mut self, max_hold: Option<Duration>) {
+        self.max_hold = max_hold;
+    }
+
+    /// The modifiers that NoteMod switches on and off
+    pub fn state_modifiers(&self) -> &[KbdKey] {
+        &self.state_modifiers
+    }
+
+    pub fn set_state_modifiers(&mut self, modifiers: Vec<KbdKey>) {
+        self.state_modifiers = modifiers;
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    pub fn set_timing_override(&mut self, timing: Timing) {
+        self.timing_override = timing;
+    }
+
+    /// How long to wait for `settle` in a sequence from a mapping with its
+    /// own `mapping_timing`
+    pub fn settle_time(&self, settle: Settle, mapping_timing: &Timing) -> u64 {
+        self.timing_override
+            .or(&mapping_timing.or(&self.timing))
+            .delay(settle)
+    }
+
+    /// Set the keys used to change octave.  If they are the keys already in
+    /// use, the game stays in the octave it was in, and otherwise it is
+    /// assumed to be in its starting octave.
+    pub fn set_octave_keys(&mut self, keys: Option<OctaveKeys>) {
+        let current = match (&self.octave, &keys) {
+            (Some(octave), Some(keys)) if octave.keys == *keys => octave.current,
+            _ => 0,
+        };
+        self.octave = keys.map(|keys| OctaveState {
+            keys,
+            current,
+            pressed: None,
+        });
+    }
+
+    /// Take one step towards getting the game into the `target` octave.
+    /// Call this repeatedly, waiting as asked, until it returns `Done`.  Only
+    /// one sequence may be stepping at a time, which the scheduler ensures.
+    pub fn octave_step(&mut self, target: i8, channel: u8) -> OctaveStep {
+        let octave = match self.octave {
+            Some(ref mut octave) => octave,
+            None => return OctaveStep::Done,
+        };
+        if let Some(key) = octave.pressed.take() {
+            let settle_ms = octave.keys.settle_ms;
+            self.key_up(&key);
+            return OctaveStep::Released(settle_ms);
+        }
+        let key = match octave.keys.next_tap(octave.current, target) {
+            Some((key, next)) => {
+                octave.current = next;
+                key.clone()
+            }
+            None => return OctaveStep::Done,
+        };
+        octave.pressed = Some(key.clone());
+        self.key_down(&key, channel, HoldLimit::Default);
+        OctaveStep::Pressed
+    }
+
+    /// Press a given key on behalf of a MIDI channel.  `hold` comes from the
+    /// mapping doing the pressing, and limits how long the key stays down.
     /// Returns `true` if an event was sent.
-    pub fn key_down(&mut self, key: &KbdKey) -> bool {
+    pub fn key_down(&mut self, key: &KbdKey, channel: u8, hold: HoldLimit) -> bool {
+        *self.channels.entry(key.clone()).or_default() |= 1 << channel;
+
+        // The most recent press decides how long the key may be held
+        let limit = match hold {
+            HoldLimit::Default => self.max_hold,
+            HoldLimit::Unlimited => None,
+            HoldLimit::Millis(msecs) => Some(Duration::from_millis(msecs)),
+        };
+        match limit {
+            Some(limit) => {
+                let now = Instant::now();
+                let pressed = self.deadlines.get(key).map_or(now, |&(pressed, _)| pressed);
+                self.deadlines.insert(key.clone(), (pressed, now + limit));
+            }
+            None => {
+                self.deadlines.remove(key);
+            }
+        }
+
         if let Some(val) = self.key_state.get(key) {
             if *val {
                 return false;
             }
         }
         self.key_state.insert(key.clone(), true);
-        ENIGO.with(|enigo| enigo.borrow_mut().key_down(KbdKey::to_enigo_key(key)));
+        self.backend.key_down(key);
         true
     }
 
@@ -39,31 +205,124 @@ impl KeyGen {
                 return false;
             }
         }
-        ENIGO.with(|enigo| enigo.borrow_mut().key_up(KbdKey::to_enigo_key(key)));
+        self.backend.key_up(key);
         self.key_state.insert(key.clone(), false);
+        self.channels.remove(key);
+        self.deadlines.remove(key);
         true
     }
 
-    /// Returns the number of keys that were reset
-    pub fn key_reset(&mut self) -> u32 {
-        let mut changes = 0;
-        for (key, pressed) in &self.key_state {
-            if *pressed {
-                ENIGO.with(|enigo| enigo.borrow_mut().key_up(KbdKey::to_enigo_key(key)));
-                changes += 1;
+    /// When the watchdog next needs to release a key, if ever
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.values().map(|&(_, deadline)| deadline).min()
+    }
+
+    /// Release every key that has been held past its deadline.
+    /// Returns each key that was released, and how long it was held.
+    pub fn release_expired(&mut self, now: Instant) -> Vec<(KbdKey, Duration)> {
+        let expired: Vec<(KbdKey, Duration)> = self
+            .deadlines
+            .iter()
+            .filter(|(_, &(_, deadline))| deadline <= now)
+            .map(|(key, &(pressed, _))| (key.clone(), now - pressed))
+            .collect();
+        for (key, _) in &expired {
+            self.key_up(key);
+        }
+        expired
+    }
+
+    /// Release every key that was pressed for a MIDI channel.
+    /// Returns the keys that were released.
+    pub fn release_channel(&mut self, channel: u8) -> Vec<KbdKey> {
+        let keys: Vec<KbdKey> = self
+            .channels
+            .iter()
+            .filter(|(_, channels)| *channels & (1 << channel) != 0)
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.into_iter().filter(|key| self.key_up(key)).collect()
+    }
+
+    /// Release every key that is held down.
+    /// Returns the keys that were released.
+    pub fn key_reset(&mut self) -> Vec<KbdKey> {
+        let mut released = vec![];
+        self.channels.clear();
+        self.deadlines.clear();
+        for (key, pressed) in self.key_state.drain() {
+            if pressed {
+                self.backend.key_up(&key);
+                released.push(key);
             }
         }
+        released
+    }
+}
+
+/// The mapping that was used for each note that is currently held down, so
+/// that the release runs the matching velocity layer.
+#[derive(Default)]
+pub struct ActiveNotes {
+    /// Held notes for each device, keyed by (channel, note)
+    devices: HashMap<String, HashMap<(u8, MidiNote), Arc<NoteMapping>>>,
+}
+
+impl ActiveNotes {
+    pub fn press(&mut self, device: &str, channel: u8, note: MidiNote, mapping: Arc<NoteMapping>) {
+        // Only allocate the device name the first time we see it
+        if let Some(notes) = self.devices.get_mut(device) {
+            notes.insert((channel, note), mapping);
+            return;
+        }
+        self.devices
+            .entry(device.to_owned())
+            .or_default()
+            .insert((channel, note), mapping);
+    }
 
-        self.key_state.clear();
-        changes
+    /// Forget every held note
+    pub fn clear(&mut self) {
+        self.devices.clear();
+    }
+
+    /// Forget the held notes on one channel, from any device
+    pub fn clear_channel(&mut self, channel: u8) {
+        for notes in self.devices.values_mut() {
+            notes.retain(|&(ch, _), _| ch != channel);
+        }
+    }
+
+    pub fn release(
+        &mut self,
+        device: &str,
+        channel: u8,
+        note: MidiNote,
+    ) -> Option<Arc<NoteMapping>> {
+        self.devices.get_mut(device)?.remove(&(channel, note))
     }
 }
 
 /// The object that gets passed to the MIDI callback, containing all our state
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct AppState {
     keygen: Arc<Mutex<KeyGen>>,
+    scheduler: Arc<Scheduler>,
     mappings: Arc<Mutex<NoteMappings>>,
+
+    active_notes: Arc<Mutex<ActiveNotes>>,
+
+    /// Where incoming messages are captured, if we're recording
+    recorder: Arc<Mutex<Option<Recorder>>>,
+
+    /// Set when the program has been asked to exit
+    stopping: Arc<AtomicBool>,
+}
+
+impl Default for AppState {
+    fn default() -> AppState {
+        AppState::with_keygen(KeyGen::new())
+    }
 }
 
 impl AppState {
@@ -71,11 +330,70 @@ impl AppState {
         AppState::default()
     }
 
+    /// Create a fresh state, and start the output thread that goes with it
+    pub fn with_keygen(keygen: KeyGen) -> AppState {
+        let keygen = Arc::new(Mutex::new(keygen));
+        AppState {
+            scheduler: Arc::new(Scheduler::new(keygen.clone())),
+            keygen,
+            mappings: Default::default(),
+            active_notes: Default::default(),
+            recorder: Default::default(),
+            stopping: Default::default(),
+        }
+    }
+
     pub fn keygen(&self) -> &Arc<Mutex<KeyGen>> {
         &self.keygen
     }
 
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
     pub fn mappings(&self) -> &Arc<Mutex<NoteMappings>> {
         &self.mappings
     }
+
+    pub fn active_notes(&self) -> &Arc<Mutex<ActiveNotes>> {
+        &self.active_notes
+    }
+
+    pub fn recorder(&self) -> &Arc<Mutex<Option<Recorder>>> {
+        &self.recorder
+    }
+
+    /// Stop all running sequences and release every key that is held down,
+    /// so nothing is left stuck.  This still works if another thread
+    /// panicked while holding one of the locks.
+    /// Returns the keys that were released.
+    pub fn release_all(&self) -> Vec<KbdKey> {
+        self.scheduler.cancel_all();
+        self.active_notes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+        self.keygen
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .key_reset()
+    }
+
+    /// Stop the sequences running for one MIDI channel and release the keys
+    /// held for it, as for an All Notes Off message.
+    /// Returns the keys that were released.
+    pub fn release_channel(&self, channel: u8) -> Vec<KbdKey> {
+        self.scheduler.cancel_channel(channel);
+        self.active_notes.lock().unwrap().clear_channel(channel);
+        self.keygen.lock().unwrap().release_channel(channel)
+    }
+
+    /// Ask the main loop to exit
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
 }
@@ -2,36 +2,40 @@
 
 use std::collections::HashMap;
 use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::panic;
+use std::process;
+use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, Arg, ArgMatches, SubCommand};
 
 use midir::{Ignore, MidiInput, MidiInputConnection};
 
 pub mod midi;
-use midi::{MidiEvent, MidiMessage, MidiNote};
+use midi::{MidiEvent, MidiMessage, MidiNote, MidiStreamParser};
 
 pub mod appstate;
-use appstate::AppState;
+use appstate::{AppState, KeyGen};
 
-pub mod notemappings;
-use notemappings::{Event, KbdKey, NoteMapping, NoteMappings};
+pub mod output;
 
-#[cfg(feature = "debug")]
-use std::fmt::Write;
+pub mod notemappings;
+use notemappings::{DuplicatePolicy, Event, KbdKey, NoteMapping, NoteMappings, Settle, Timing};
 
-/// The amount of time to wait for a keyboard modifier to stick
-const MOD_DELAY_MS: u64 = 150;
+pub mod recorder;
+use recorder::Recorder;
 
-/// The amount of time to wait for a keydown event to stick
-const KEY_DELAY_MS: u64 = 40;
+pub mod scheduler;
+use scheduler::{Clock, Phase, Voice};
 
-/// The amount of time required for system events, such as Esc
-const SYS_DELAY_MS: u64 = 400;
+pub mod smf;
+use smf::Smf;
 
-/// A small delay required when switching between octaves.
-const OCTAVE_DELAY_MS: u64 = 10;
+#[cfg(feature = "debug")]
+use std::fmt::Write;
 
 fn main() {
     let matches = App::new("Midi Perform")
@@ -56,7 +60,150 @@ fn main() {
                 .short("f")
                 .long("mappings")
                 .help("Load a mappings file (line format: note channel keydown keyup)")
-                .value_name("MAPPINGS"),
+                .value_name("MAPPINGS")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("duplicates")
+                .long("duplicates")
+                .help("What to do when a note is mapped more than once")
+                .value_name("POLICY")
+                .possible_values(&["first", "last", "error"])
+                .default_value("last")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("middle-c")
+                .long("middle-c")
+                .help("Octave name of middle C (note 60) in note names")
+                .value_name("OCTAVE")
+                .possible_values(&["C4", "C3"])
+                .default_value("C4")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .help("Where to send key presses")
+                .value_name("BACKEND")
+                .possible_values(output::BACKEND_NAMES)
+                .default_value("enigo")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("timing")
+                .long("timing")
+                .help("Override a settle time from the mappings, e.g. \"key=60\"")
+                .value_name("NAME=MS")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("max-hold")
+                .long("max-hold")
+                .help("Release keys that have been held for longer than this many milliseconds")
+                .value_name("MS")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Print the keys that would be pressed instead of pressing them")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("dump-mappings")
+                .long("dump-mappings")
+                .help("Save the mappings in use, or the built-in ones, to a .toml profile and exit")
+                .value_name("PATH"),
+        )
+        .arg(
+            Arg::with_name("input")
+                .short("i")
+                .long("input")
+                .help("Read raw MIDI bytes from a file, serial port or pipe (\"-\" for stdin)")
+                .value_name("PATH")
+                .conflicts_with("device"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .short("r")
+                .long("record")
+                .help("Record everything received to a MIDI file, saved on Ctrl+C")
+                .value_name("FILE")
+                .conflicts_with("input"),
+        )
+        .arg(
+            Arg::with_name("record-format")
+                .long("record-format")
+                .help("MIDI file type to record: 0 (one track) or 1 (one track per device)")
+                .value_name("TYPE")
+                .possible_values(&["0", "1"])
+                .default_value("0"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check a mappings file for problems and show what each note does")
+                .arg(
+                    Arg::with_name("PROFILE")
+                        .help("The mappings file to check")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("play")
+                .about("Play a Standard MIDI File through the mappings")
+                .arg(
+                    Arg::with_name("FILE")
+                        .help("The .mid file to play")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("tempo")
+                        .short("t")
+                        .long("tempo")
+                        .help("Tempo scale, e.g. 0.5 for half speed")
+                        .value_name("SCALE")
+                        .default_value("1.0"),
+                )
+                .arg(
+                    Arg::with_name("start")
+                        .short("s")
+                        .long("start")
+                        .help("Start playing this many seconds into the song")
+                        .value_name("SECONDS")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("track")
+                        .long("track")
+                        .help("Only play the given track (may be repeated)")
+                        .value_name("TRACK")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("channel")
+                        .long("channel")
+                        .help("Only play the given channel (may be repeated)")
+                        .value_name("CHANNEL")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("countdown")
+                        .short("c")
+                        .long("countdown")
+                        .help("Seconds to wait before playing, to switch to the game window")
+                        .value_name("SECONDS")
+                        .default_value("3"),
+                ),
         )
         .get_matches();
 
@@ -64,76 +211,101 @@ fn main() {
         list_devices().expect("unable to list MIDI devices");
         return;
     }
+    if let Some(Ok(convention)) = matches.value_of("middle-c").map(str::parse) {
+        midi::set_octave_convention(convention);
+    }
+
+    // Checking a profile doesn't need any devices, so do it before opening them
+    if let Some(check_matches) = matches.subcommand_matches("check") {
+        let filename = check_matches.value_of("PROFILE").unwrap_or_default();
+        let backend_name = matches.value_of("output").unwrap_or("enigo");
+        match check(filename, backend_name) {
+            Ok(true) => return,
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                eprintln!("unable to read {}: {}", filename, e);
+                process::exit(1);
+            }
+        }
+    }
+
     let device_name = matches.value_of("device");
-    let mappings_file = matches.value_of("mappings");
-    run(device_name, mappings_file).unwrap();
+    let profile = match ProfileOptions::from_matches(&matches) {
+        Ok(profile) => profile,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(path) = matches.value_of("dump-mappings") {
+        // Only structured profiles can describe every mapping
+        if !path.ends_with(".toml") {
+            eprintln!("mappings can only be saved to a .toml profile");
+            process::exit(1);
+        }
+        read_mappings(&profile)
+            .export(path)
+            .expect("unable to save mappings");
+        println!("Saved mappings to {}", path);
+        return;
+    }
+    let backend_name = if matches.is_present("dry-run") {
+        "dry-run"
+    } else {
+        matches.value_of("output").unwrap_or("enigo")
+    };
+    let backend = output::new_backend(backend_name).expect("unable to open output backend");
+    let mut keygen = KeyGen::with_backend(backend);
+    if let Some(max_hold) = matches.value_of("max-hold") {
+        let msecs = match max_hold.parse() {
+            Ok(msecs) => msecs,
+            Err(_) => {
+                eprintln!("--max-hold must be a number of milliseconds");
+                process::exit(1);
+            }
+        };
+        keygen.set_max_hold(Some(Duration::from_millis(msecs)));
+    }
+    let app_state = AppState::with_keygen(keygen);
+    install_handlers(&app_state, matches.value_of("input").is_some())
+        .expect("unable to install Ctrl+C handler");
+
+    if let Some(play_matches) = matches.subcommand_matches("play") {
+        let options = PlayOptions::from_matches(play_matches).unwrap();
+        play(&app_state, &options, &profile).unwrap();
+        return;
+    }
+    if let Some(input) = matches.value_of("input") {
+        run_input(&app_state, input, &profile).unwrap();
+        return;
+    }
+    let record_format = match matches.value_of("record-format") {
+        Some("1") => 1,
+        _ => 0,
+    };
+    let record = matches.value_of("record").map(|path| (path, record_format));
+    run(&app_state, device_name, &profile, record).unwrap();
 }
 
 /// This function is called for every message that gets passed in.
-fn midi_callback(_timestamp_us: u64, raw_message: &[u8], app_state: &AppState) {
-    let mut keygen = app_state.keygen().lock().unwrap();
+fn midi_callback(
+    timestamp_us: u64,
+    raw_message: &[u8],
+    port_name: &str,
+    clock: &mut Clock,
+    app_state: &AppState,
+) {
+    if let Some(ref mut recorder) = *app_state.recorder().lock().unwrap() {
+        recorder.record(port_name, timestamp_us, raw_message);
+    }
 
     if let Ok(msg) = MidiMessage::new(raw_message) {
-        match app_state
-            .mappings()
-            .lock()
-            .unwrap()
-            .find(*msg.note(), msg.channel(), None)
-        {
-            Some(note_mapping) => {
-                let sequence = match *msg.event() {
-                    MidiEvent::NoteOn => &note_mapping.on,
-                    MidiEvent::NoteOff => &note_mapping.off,
-                };
-
-                //println!("Found note mapping: {:?} for event {:?}, running sequence {:?}", note_mapping, msg.event(), sequence);
-                for event in sequence {
-                    match *event {
-                        notemappings::Event::Delay(msecs) => {
-                            thread::sleep(Duration::from_millis(msecs))
-                        }
-                        notemappings::Event::KeyDown(ref k) => {
-                            keygen.key_down(&k);
-                        }
-                        notemappings::Event::KeyUp(ref k) => {
-                            keygen.key_up(&k);
-                        }
-
-                        // For NoteMod, which goes at the top of a note, see if we need to change
-                        // the current set of modifiers.  If so, pause a short while.
-                        // This enables fast switching between notes in the same octave, where no
-                        // keychange is required.
-                        notemappings::Event::NoteMod(ref kopt) => {
-                            let mut changes = 0;
-                            let key_mods = vec![KbdKey::Shift, KbdKey::Control];
-                            if let Some(ref k) = *kopt {
-                                for key_mod in key_mods {
-                                    if &key_mod == k {
-                                        if keygen.key_down(&key_mod) {
-                                            changes += 1;
-                                        }
-                                    } else if keygen.key_up(&key_mod) {
-                                        changes += 1;
-                                    }
-                                }
-                            } else {
-                                for key_mod in key_mods {
-                                    if keygen.key_up(&key_mod) {
-                                        changes += 1;
-                                    }
-                                }
-                            }
-                            if changes > 0 {
-                                thread::sleep(Duration::from_millis(OCTAVE_DELAY_MS));
-                            }
-                        }
-                    }
-                }
-            }
-            _ => {
-                println!("No note mapping for {:?} @ {:?}", msg.note(), msg.channel());
-            }
-        }
+        handle_message(
+            &msg,
+            Some(port_name),
+            clock.instant(timestamp_us),
+            app_state,
+        );
     }
 
     #[cfg(feature = "debug")]
@@ -146,6 +318,81 @@ fn midi_callback(_timestamp_us: u64, raw_message: &[u8], app_state: &AppState) {
     }
 }
 
+/// Run the mapping for a single parsed message, regardless of where it came from.
+/// `device` is the name of the source, used to pick device-specific mappings,
+/// and `due` is when the message was received.
+fn handle_message(msg: &MidiMessage, device: Option<&str>, due: Instant, app_state: &AppState) {
+    // Don't press anything new once the held keys have been released for exit
+    if app_state.is_stopping() {
+        return;
+    }
+
+    if app_state.mappings().lock().unwrap().is_panic(msg, device) {
+        release_held_keys(app_state, "for panic trigger");
+        return;
+    }
+    if let Some(mode) = msg.channel_mode() {
+        if mode.silences_notes() {
+            let released = app_state.release_channel(msg.channel());
+            report_released(
+                &released,
+                &format!("for {:?} on channel {}", mode, msg.channel()),
+            );
+        }
+        return;
+    }
+
+    // Only notes are mapped for now.  Other channel messages (controllers,
+    // program changes, pitch bend, pressure) are parsed but not acted upon.
+    let note = match msg.note() {
+        Some(note) => note,
+        None => return,
+    };
+
+    // Keep the mappings locked until the sequence is queued.  Otherwise a
+    // reload could release every key in between, and this note would then
+    // press a key from the old mappings.
+    let mappings = app_state.mappings().lock().unwrap();
+    let note_mapping = match *msg.event() {
+        MidiEvent::NoteOn => {
+            let found = mappings.find(note, msg.channel(), msg.velocity(), device);
+            if let Some(ref mapping) = found {
+                app_state.active_notes().lock().unwrap().press(
+                    device.unwrap_or_default(),
+                    msg.channel(),
+                    note,
+                    mapping.clone(),
+                );
+            }
+            found
+        }
+        // Release whichever velocity layer was pressed
+        MidiEvent::NoteOff => app_state
+            .active_notes()
+            .lock()
+            .unwrap()
+            .release(device.unwrap_or_default(), msg.channel(), note)
+            .or_else(|| mappings.find(note, msg.channel(), None, device)),
+        _ => return,
+    };
+
+    match note_mapping {
+        Some(note_mapping) => {
+            let phase = match *msg.event() {
+                MidiEvent::NoteOn => Phase::On,
+                _ => Phase::Off,
+            };
+            let voice = Voice::new(device, msg.channel(), note);
+            app_state
+                .scheduler()
+                .schedule(due, note_mapping, phase, voice);
+        }
+        _ => {
+            println!("No note mapping for {} @ {}", note, msg.channel());
+        }
+    }
+}
+
 fn generate_old_mappings(mappings: &mut NoteMappings) {
     let keys = vec![
         'q', '2', 'w', '3', 'e', 'r', '5', 't', '6', 'y', '7', 'u', 'i',
@@ -153,59 +400,56 @@ fn generate_old_mappings(mappings: &mut NoteMappings) {
 
     for (key_idx, key) in keys.iter().enumerate() {
         let base = MidiNote::C3.index();
-        let mut note_mapping_lo = NoteMapping::new(
-            MidiNote::new(key_idx as u8 + base).expect("Invalid note index"),
-            0,
-            None,
-        );
-        let mut note_mapping_mid = NoteMapping::new(
-            MidiNote::new(key_idx as u8 + base + 12).expect("Invalid note index"),
-            0,
-            None,
-        );
-        let mut note_mapping_hi = NoteMapping::new(
-            MidiNote::new(key_idx as u8 + base + 24).expect("Invalid note index"),
-            0,
-            None,
-        );
-
-        note_mapping_lo.on =
-            NoteMapping::down_event(*key, Some(KbdKey::Control), Some(MOD_DELAY_MS));
-        note_mapping_lo.off =
-            NoteMapping::up_event(*key, Some(KbdKey::Control), Some(MOD_DELAY_MS));
-
-        note_mapping_mid.on = NoteMapping::down_event(*key, None, None);
-        note_mapping_mid.off = NoteMapping::up_event(*key, None, None);
-
-        note_mapping_hi.on = NoteMapping::down_event(*key, Some(KbdKey::Shift), Some(MOD_DELAY_MS));
-        note_mapping_hi.off = NoteMapping::up_event(*key, Some(KbdKey::Shift), Some(MOD_DELAY_MS));
-
-        mappings.add(note_mapping_lo);
-        mappings.add(note_mapping_mid);
-        mappings.add(note_mapping_hi);
+        let note_lo = MidiNote::new(key_idx as u8 + base).expect("Invalid note index");
+        let mut note_mapping_lo = NoteMapping::new(note_lo, 0, None);
+        let note_mid = MidiNote::new(key_idx as u8 + base + 12).expect("Invalid note index");
+        let mut note_mapping_mid = NoteMapping::new(note_mid, 0, None);
+        let note_hi = MidiNote::new(key_idx as u8 + base + 24).expect("Invalid note index");
+        let mut note_mapping_hi = NoteMapping::new(note_hi, 0, None);
+
+        note_mapping_lo.on = NoteMapping::down_event(*key, &[KbdKey::Control]);
+        note_mapping_lo.off = NoteMapping::up_event(*key);
+
+        note_mapping_mid.on = NoteMapping::down_event(*key, &[]);
+        note_mapping_mid.off = NoteMapping::up_event(*key);
+
+        note_mapping_hi.on = NoteMapping::down_event(*key, &[KbdKey::Shift]);
+        note_mapping_hi.off = NoteMapping::up_event(*key);
+
+        // The top key's low and middle notes are already mapped by the
+        // bottom key an octave up, and those mappings win.
+        for (note, mapping) in [
+            (note_lo, note_mapping_lo),
+            (note_mid, note_mapping_mid),
+            (note_hi, note_mapping_hi),
+        ] {
+            if mappings.find(note, 0, None, None).is_none() {
+                mappings.add(mapping).expect("Duplicate built-in mapping");
+            }
+        }
     }
 
     // Add pad buttons on the top of my keyboard, which are on channel 9.
-    let pads = vec!['z', 'x', 'c', 'v', 'b', 'n', 'm', ','];
+    let pads = ['z', 'x', 'c', 'v', 'b', 'n', 'm', ','];
     for (pad_idx, pad) in pads.iter().enumerate() {
         let seq = vec![
-            Event::NoteMod(None), // Ensure no modifier keys are pressed at the start
+            Event::NoteMod(vec![]), // Ensure no modifier keys are pressed at the start
             // Press Escape twice to clear any dialogs, and to potentially
             // exit the current Perform session.
             Event::KeyDown(KbdKey::Escape),
-            Event::Delay(KEY_DELAY_MS),
+            Event::Settle(Settle::Key),
             Event::KeyUp(KbdKey::Escape),
-            Event::Delay(SYS_DELAY_MS),
+            Event::Settle(Settle::System),
             // Hold Control, Alt, and Shift.
             Event::KeyDown(KbdKey::Control),
             Event::KeyDown(KbdKey::Alt),
             Event::KeyDown(KbdKey::Shift),
             // Let the modifier keys get registered
-            Event::Delay(MOD_DELAY_MS),
+            Event::Settle(Settle::Modifier),
             Event::KeyDown(KbdKey::Layout(*pad)),
-            Event::Delay(KEY_DELAY_MS),
+            Event::Settle(Settle::Key),
             Event::KeyUp(KbdKey::Layout(*pad)),
-            Event::Delay(MOD_DELAY_MS),
+            Event::Settle(Settle::Modifier),
             Event::KeyUp(KbdKey::Shift),
             Event::KeyUp(KbdKey::Alt),
             Event::KeyUp(KbdKey::Control),
@@ -217,25 +461,288 @@ fn generate_old_mappings(mappings: &mut NoteMappings) {
             None,
         );
         pad_mapping.on = seq;
-        mappings.add(pad_mapping);
+        mappings
+            .add(pad_mapping)
+            .expect("Duplicate built-in mapping");
     }
 }
 
-fn run(midi_name: Option<&str>, mappings_file: Option<&str>) -> Result<(), Box<dyn Error>> {
-    let mut midi_ports: HashMap<String, MidiInputConnection<()>> = HashMap::new();
-    let app_state = AppState::new();
+/// Settings that control how the mappings are loaded
+struct ProfileOptions<'a> {
+    mappings_file: Option<&'a str>,
+    duplicates: DuplicatePolicy,
 
-    match mappings_file {
-        Some(filename) => app_state
-            .mappings()
-            .lock()
-            .unwrap()
-            .import(filename)
-            .unwrap(),
-        None => generate_old_mappings(&mut app_state.mappings().lock().unwrap()),
+    /// Settle times given on the command line
+    timing: Timing,
+}
+
+impl<'a> ProfileOptions<'a> {
+    fn from_matches(matches: &'a ArgMatches) -> Result<ProfileOptions<'a>, Box<dyn Error>> {
+        let mut timing = Timing::new();
+        for setting in matches.values_of("timing").into_iter().flatten() {
+            timing
+                .parse_setting(setting)
+                .map_err(|e| format!("--timing {}: {}", setting, e))?;
+        }
+        Ok(ProfileOptions {
+            mappings_file: matches.value_of("mappings"),
+            duplicates: matches.value_of("duplicates").unwrap_or("last").parse()?,
+            timing,
+        })
+    }
+}
+
+/// The mappings from the mappings file, or the built-in ones if there isn't
+/// one.  Exits if the file can't be loaded.
+fn read_mappings(profile: &ProfileOptions) -> NoteMappings {
+    let mut mappings = NoteMappings::new();
+    mappings.set_duplicate_policy(profile.duplicates);
+    match profile.mappings_file {
+        Some(filename) => {
+            if let Err(e) = mappings.import(filename) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        None => generate_old_mappings(&mut mappings),
     };
+    mappings
+}
+
+fn load_mappings(app_state: &AppState, profile: &ProfileOptions) {
+    let mappings = read_mappings(profile);
+    configure_keygen(app_state, &mappings, profile);
+    *app_state.mappings().lock().unwrap() = mappings;
+}
+
+/// Pass the settings that come with the mappings on to the key generator
+fn configure_keygen(app_state: &AppState, mappings: &NoteMappings, profile: &ProfileOptions) {
+    let mut keygen = app_state.keygen().lock().unwrap();
+    keygen.set_state_modifiers(mappings.modifiers());
+    keygen.set_octave_keys(mappings.octave_keys().cloned());
+    keygen.set_timing(*mappings.timing());
+    keygen.set_timing_override(profile.timing);
+}
+
+/// Load the mappings file again after it has changed.  The new mappings
+/// only replace the old ones if the whole file loads, and every key held for
+/// the old ones is released first, since their note off may now do
+/// something else.
+fn reload_mappings(app_state: &AppState, filename: &str, profile: &ProfileOptions) {
+    let mut staged = NoteMappings::new();
+    staged.set_duplicate_policy(profile.duplicates);
+    if let Err(e) = staged.import(filename) {
+        println!(
+            "Keeping the current mappings, since {} didn't load:",
+            filename
+        );
+        println!("{}", e);
+        return;
+    }
+
+    // Hold on to the mappings while switching, since `handle_message` keeps
+    // them locked from looking up a note until its sequence is queued.  No
+    // note from the old mappings can be queued after the keys are released.
+    let mut mappings = app_state.mappings().lock().unwrap();
+    release_held_keys(app_state, "before switching mappings");
+    configure_keygen(app_state, &staged, profile);
+    *mappings = staged;
+    println!("Reloaded mappings from {}", filename);
+}
+
+/// When a file was last changed, if that can be found out
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
 
+/// Read MIDI from a byte stream rather than a MIDI port.  This works with
+/// anything that produces raw MIDI, such as a serial port, a raw MIDI device
+/// node, a pipe or a file.
+fn run_input(
+    app_state: &AppState,
+    path: &str,
+    profile: &ProfileOptions,
+) -> Result<(), Box<dyn Error>> {
+    load_mappings(app_state, profile);
+
+    let mut input: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    println!("Reading MIDI from {}", path);
+
+    let mut parser = MidiStreamParser::new();
+    let mut buffer = [0; 256];
     loop {
+        let count = input.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        for msg in parser.feed(&buffer[..count]) {
+            handle_message(&msg, Some(path), Instant::now(), app_state);
+        }
+    }
+    app_state.scheduler().wait_idle();
+    release_held_keys(app_state, "at the end of the input");
+    Ok(())
+}
+
+/// Settings for the "play" subcommand
+struct PlayOptions {
+    path: String,
+    tempo: f64,
+    start_us: u64,
+    tracks: Vec<usize>,
+    channels: Vec<u8>,
+    countdown: u64,
+}
+
+impl PlayOptions {
+    fn from_matches(matches: &ArgMatches) -> Result<PlayOptions, Box<dyn Error>> {
+        let tempo = matches.value_of("tempo").unwrap_or("1.0").parse::<f64>()?;
+        if tempo <= 0.0 {
+            return Err("tempo scale must be greater than zero".into());
+        }
+        let start = matches.value_of("start").unwrap_or("0").parse::<f64>()?;
+        let mut tracks = vec![];
+        for track in matches.values_of("track").into_iter().flatten() {
+            tracks.push(track.parse()?);
+        }
+        let mut channels = vec![];
+        for channel in matches.values_of("channel").into_iter().flatten() {
+            channels.push(channel.parse()?);
+        }
+        Ok(PlayOptions {
+            path: matches.value_of("FILE").unwrap_or_default().to_owned(),
+            tempo,
+            start_us: (start.max(0.0) * 1_000_000.0) as u64,
+            tracks,
+            channels,
+            countdown: matches.value_of("countdown").unwrap_or("0").parse()?,
+        })
+    }
+}
+
+/// Play a MIDI file, sending each note through the mappings as if it had
+/// been played live.
+fn play(
+    app_state: &AppState,
+    options: &PlayOptions,
+    profile: &ProfileOptions,
+) -> Result<(), Box<dyn Error>> {
+    load_mappings(app_state, profile);
+
+    let song = Smf::open(&options.path)?;
+    println!(
+        "Loaded {} (type {}, {} tracks, {} events)",
+        options.path,
+        song.format,
+        song.track_count,
+        song.events.len()
+    );
+
+    for remaining in (1..=options.countdown).rev() {
+        println!("Playing in {}...", remaining);
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    let start = Instant::now();
+    for event in &song.events {
+        if app_state.is_stopping() {
+            break;
+        }
+        if event.time_us < options.start_us
+            || (!options.tracks.is_empty() && !options.tracks.contains(&event.track))
+            || (!options.channels.is_empty()
+                && !options.channels.contains(&event.message.channel()))
+        {
+            continue;
+        }
+
+        let offset_us = ((event.time_us - options.start_us) as f64 / options.tempo) as u64;
+        let due = start + Duration::from_micros(offset_us);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+        handle_message(&event.message, None, due, app_state);
+    }
+
+    // Don't leave anything held down if the song ended mid-note
+    app_state.scheduler().wait_idle();
+    release_held_keys(app_state, "at the end of the song");
+    println!("Finished playing {}", options.path);
+    Ok(())
+}
+
+/// Release every held key and say which ones were released, if any
+fn release_held_keys(app_state: &AppState, why: &str) {
+    report_released(&app_state.release_all(), why);
+}
+
+fn report_released(released: &[KbdKey], why: &str) {
+    if !released.is_empty() {
+        let names: Vec<String> = released.iter().map(KbdKey::to_string).collect();
+        println!("Released {} {}", names.join(", "), why);
+    }
+}
+
+/// Make sure no keys are left held down if the program is interrupted or
+/// panics.  Both stop the program, since the state may no longer make sense.
+///
+/// Reading from `--input` blocks, so there is no loop to notice the request
+/// to stop, and `exit_on_interrupt` exits directly instead.
+fn install_handlers(app_state: &AppState, exit_on_interrupt: bool) -> Result<(), ctrlc::Error> {
+    let app_state_sig = app_state.clone();
+    ctrlc::set_handler(move || {
+        app_state_sig.stop();
+        release_held_keys(&app_state_sig, "on Ctrl+C");
+        if exit_on_interrupt {
+            process::exit(130);
+        }
+    })?;
+
+    let app_state_panic = app_state.clone();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        app_state_panic.stop();
+
+        // The panicking thread may be holding the locks that releasing keys
+        // needs, and only lets go of them once the hook returns.  Release from
+        // another thread, and give it a moment to finish before carrying on.
+        let (done_tx, done_rx) = mpsc::channel();
+        let app_state_thr = app_state_panic.clone();
+        thread::spawn(move || {
+            release_held_keys(&app_state_thr, "after a panic");
+            let _ = done_tx.send(());
+        });
+        let _ = done_rx.recv_timeout(Duration::from_millis(500));
+    }));
+    Ok(())
+}
+
+/// Monitor MIDI devices until Ctrl+C is pressed.  If `record` is set to a
+/// path and MIDI file type, everything received is saved there on exit.
+fn run(
+    app_state: &AppState,
+    midi_name: Option<&str>,
+    profile: &ProfileOptions,
+    record: Option<(&str, u16)>,
+) -> Result<(), Box<dyn Error>> {
+    let mut midi_ports: HashMap<String, MidiInputConnection<()>> = HashMap::new();
+    load_mappings(app_state, profile);
+    let mut mappings_modified = profile.mappings_file.and_then(modified_time);
+
+    if let Some((path, _)) = record {
+        *app_state.recorder().lock().unwrap() = Some(Recorder::new());
+        println!("Recording to {}, press Ctrl+C to stop", path);
+    }
+
+    while !app_state.is_stopping() {
         let ports = MidiInput::new("perform-count")
             .expect("Couldn't create midi input")
             .ports();
@@ -262,14 +769,20 @@ fn run(midi_name: Option<&str>, mappings_file: Option<&str>) -> Result<(), Box<d
                         }
                     }
 
-                    // This device is new.
+                    // This device is new.  Its timestamps start from zero,
+                    // even if it was connected before.
+                    if let Some(ref mut recorder) = *app_state.recorder().lock().unwrap() {
+                        recorder.connected(&name);
+                    }
                     midi_in.ignore(Ignore::None);
                     let app_state_thr = app_state.clone();
+                    let port_name = name.clone();
+                    let mut clock = Clock::new();
                     match midi_in.connect(
                         &port,
                         "key monitor",
                         move |ts, raw_msg, _ignored| {
-                            midi_callback(ts, raw_msg, &app_state_thr);
+                            midi_callback(ts, raw_msg, &port_name, &mut clock, &app_state_thr);
                         },
                         (),
                     ) {
@@ -292,9 +805,127 @@ fn run(midi_name: Option<&str>, mappings_file: Option<&str>) -> Result<(), Box<d
         for name in to_delete {
             println!("Disconnected from {}", name);
             midi_ports.remove(&name);
+            release_held_keys(app_state, &format!("after {} was disconnected", name));
+        }
+
+        // Pick up changes to the mappings file, so it can be tuned mid-session
+        if let Some(filename) = profile.mappings_file {
+            let modified = modified_time(filename);
+            if modified != mappings_modified {
+                mappings_modified = modified;
+                reload_mappings(app_state, filename, profile);
+            }
         }
         thread::sleep(Duration::from_secs(1));
     }
+
+    // Close the ports first, so nothing more gets recorded or pressed
+    midi_ports.clear();
+    release_held_keys(app_state, "on exit");
+    if let Some((path, format)) = record {
+        if let Some(recorder) = app_state.recorder().lock().unwrap().take() {
+            recorder.save(path, format)?;
+            println!("Saved {} messages to {}", recorder.len(), path);
+        }
+    }
+    Ok(())
+}
+
+/// Load a mappings file without opening any devices, print every problem
+/// with it and then what each note does.  Returns whether it had no problems.
+fn check(filename: &str, backend_name: &str) -> Result<bool, Box<dyn Error>> {
+    // Mapping a note twice is a mistake here, even though playing allows it
+    let (mappings, diagnostics) = NoteMappings::read(filename, DuplicatePolicy::Error)?;
+    let mut problems = diagnostics.len();
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+    for unreachable in mappings.unreachable() {
+        println!("{}: {}", filename, unreachable);
+        problems += 1;
+    }
+
+    // Every key that the profile could press, each reported only once
+    let mut keys = mappings.modifiers();
+    if let Some(octave_keys) = mappings.octave_keys() {
+        keys.push(octave_keys.up.clone());
+        keys.push(octave_keys.down.clone());
+    }
+    for mapping in mappings.iter() {
+        for event in mapping.on.iter().chain(&mapping.off) {
+            if let Event::KeyDown(key) | Event::KeyUp(key) = event {
+                keys.push(key.clone());
+            }
+        }
+    }
+    let mut reported: Vec<KbdKey> = vec![];
+    for key in keys {
+        if !output::can_press(backend_name, &key) && !reported.contains(&key) {
+            println!(
+                "{}: key {} can't be pressed with the {} output",
+                filename,
+                key.to_string().escape_debug(),
+                backend_name
+            );
+            reported.push(key);
+            problems += 1;
+        }
+    }
+
+    for conflict in mappings.conflicts() {
+        println!("Warning: {}", conflict);
+    }
+
+    // What each note does, with panic buttons first since they come first
+    let mut rows = vec![];
+    for panic in mappings.panics() {
+        let mut trigger = format!("{} on channel {}", panic.trigger, panic.channel);
+        if let Some(ref name) = panic.instrument_name {
+            trigger.push_str(&format!(" for \"{}\"", name));
+        }
+        rows.push((trigger, "panic".to_owned(), String::new()));
+    }
+    let describe = |events: &[Event]| {
+        let events: Vec<String> = events
+            .iter()
+            .map(|event| event.to_string().escape_debug().to_string())
+            .collect();
+        events.join(", ")
+    };
+    for mapping in mappings.iter() {
+        let off = if mapping.off.is_empty() {
+            String::new()
+        } else {
+            format!("off: {}", describe(&mapping.off))
+        };
+        rows.push((
+            mapping.to_string(),
+            format!("on:  {}", describe(&mapping.on)),
+            off,
+        ));
+    }
+    let width = rows
+        .iter()
+        .map(|(note, _, _)| note.len())
+        .max()
+        .unwrap_or(0);
+    if !rows.is_empty() {
+        println!();
+    }
+    for (note, on, off) in &rows {
+        println!("{:width$}  {}", note, on);
+        if !off.is_empty() {
+            println!("{:width$}  {}", "", off);
+        }
+    }
+
+    println!();
+    match problems {
+        0 => println!("{}: no problems found", filename),
+        1 => println!("{}: 1 problem found", filename),
+        n => println!("{}: {} problems found", filename, n),
+    }
+    Ok(problems == 0)
 }
 
 fn list_devices() -> Result<(), Box<dyn Error>> {
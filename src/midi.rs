@@ -1,10 +1,72 @@
-#[derive(Debug, PartialEq)]
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum MidiEvent {
     NoteOn,
     NoteOff,
+    /// Per-note pressure change (polyphonic key pressure)
+    PolyAftertouch,
+    ControlChange,
+    ProgramChange,
+    /// Pressure applied to the whole channel (aftertouch)
+    ChannelPressure,
+    PitchBend,
+}
+
+/// Names of the twelve notes of an octave, as used when printing notes
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// The octave number that middle C (note 60) is written with.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum OctaveConvention {
+    /// Scientific pitch notation, where middle C is C4 and note 0 is C-1.
+    /// This is what the `MidiNote` variants are named after.
+    C4,
+    /// Middle C is C3 and note 0 is C-2, as used by Yamaha and many DAWs.
+    C3,
+}
+
+impl OctaveConvention {
+    /// The octave number of the lowest MIDI octave.
+    fn lowest_octave(self) -> i32 {
+        match self {
+            OctaveConvention::C4 => -1,
+            OctaveConvention::C3 => -2,
+        }
+    }
 }
 
-#[derive(Debug, PartialOrd, PartialEq, Copy, Clone)]
+impl FromStr for OctaveConvention {
+    type Err = MidiError;
+    fn from_str(s: &str) -> Result<OctaveConvention, MidiError> {
+        match s.to_lowercase().as_str() {
+            "c4" => Ok(OctaveConvention::C4),
+            "c3" => Ok(OctaveConvention::C3),
+            _ => Err(MidiError::Unparseable),
+        }
+    }
+}
+
+static MIDDLE_C_IS_C3: AtomicBool = AtomicBool::new(false);
+
+/// Select how octave numbers are read and written for note names.
+pub fn set_octave_convention(convention: OctaveConvention) {
+    MIDDLE_C_IS_C3.store(convention == OctaveConvention::C3, Ordering::Relaxed);
+}
+
+pub fn octave_convention() -> OctaveConvention {
+    if MIDDLE_C_IS_C3.load(Ordering::Relaxed) {
+        OctaveConvention::C3
+    } else {
+        OctaveConvention::C4
+    }
+}
+
+#[derive(Debug, PartialOrd, PartialEq, Eq, Hash, Copy, Clone)]
 #[allow(dead_code)]
 pub enum MidiNote {
     Cn = 0,
@@ -137,12 +199,45 @@ pub enum MidiNote {
     G9 = 127,
 }
 
+/// The value of a pitch bend message when the wheel is at rest.
+pub const PITCH_BEND_CENTER: u16 = 0x2000;
+
+/// A channel mode message, which is a ControlChange using one of the
+/// controller numbers 120-127.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ChannelMode {
+    AllSoundOff,
+    ResetAllControllers,
+    LocalControl(bool),
+    AllNotesOff,
+    OmniOff,
+    OmniOn,
+    /// The number of channels to use, or 0 for as many as there are voices
+    MonoOn(u8),
+    PolyOn,
+}
+
+impl ChannelMode {
+    /// Whether this message should silence every note on the channel.
+    /// Besides All Notes Off and All Sound Off, the omni and mono/poly
+    /// messages imply an All Notes Off.
+    pub fn silences_notes(self) -> bool {
+        !matches!(
+            self,
+            ChannelMode::ResetAllControllers | ChannelMode::LocalControl(_)
+        )
+    }
+}
+
+/// A single channel voice message.  The two data bytes are kept as they
+/// appeared on the wire, and are interpreted by the accessors according to
+/// the event type.
 #[derive(Debug)]
 pub struct MidiMessage {
     event: MidiEvent,
     channel: u8,
-    note: MidiNote,
-    velocity: u8,
+    data1: u8,
+    data2: u8,
 }
 
 #[derive(Debug)]
@@ -159,326 +254,405 @@ impl MidiNote {
             return Err(MidiError::NoteOutOfRange);
         }
         use std::mem;
-        Ok(unsafe { mem::transmute(val) })
+        Ok(unsafe { mem::transmute::<u8, MidiNote>(val) })
     }
 
-    #[allow(clippy::cognitive_complexity)]
-    pub fn new_from_text(txt: &str) -> Result<MidiNote, MidiError> {
-        let s = txt.to_lowercase();
-        if s.starts_with('#') {
-            Err(MidiError::Unparseable)
-        } else if s.starts_with("cn") {
-            Ok(MidiNote::Cn)
-        } else if s.starts_with("csn") {
-            Ok(MidiNote::Csn)
-        } else if s.starts_with("dn") {
-            Ok(MidiNote::Dn)
-        } else if s.starts_with("dsn") {
-            Ok(MidiNote::Dsn)
-        } else if s.starts_with("en") {
-            Ok(MidiNote::En)
-        } else if s.starts_with("fn") {
-            Ok(MidiNote::Fn)
-        } else if s.starts_with("fsn") {
-            Ok(MidiNote::Fsn)
-        } else if s.starts_with("gn") {
-            Ok(MidiNote::Gn)
-        } else if s.starts_with("gsn") {
-            Ok(MidiNote::Gsn)
-        } else if s.starts_with("an") {
-            Ok(MidiNote::An)
-        } else if s.starts_with("asn") {
-            Ok(MidiNote::Asn)
-        } else if s.starts_with("bn") {
-            Ok(MidiNote::Bn)
-        } else if s.starts_with("c0") {
-            Ok(MidiNote::C0)
-        } else if s.starts_with("cs0") {
-            Ok(MidiNote::Cs0)
-        } else if s.starts_with("d0") {
-            Ok(MidiNote::D0)
-        } else if s.starts_with("ds0") {
-            Ok(MidiNote::Ds0)
-        } else if s.starts_with("e0") {
-            Ok(MidiNote::E0)
-        } else if s.starts_with("f0") {
-            Ok(MidiNote::F0)
-        } else if s.starts_with("fs0") {
-            Ok(MidiNote::Fs0)
-        } else if s.starts_with("g0") {
-            Ok(MidiNote::G0)
-        } else if s.starts_with("gs0") {
-            Ok(MidiNote::Gs0)
-        } else if s.starts_with("a0") {
-            Ok(MidiNote::A0)
-        } else if s.starts_with("as0") {
-            Ok(MidiNote::As0)
-        } else if s.starts_with("b0") {
-            Ok(MidiNote::B0)
-        } else if s.starts_with("c1") {
-            Ok(MidiNote::C1)
-        } else if s.starts_with("cs1") {
-            Ok(MidiNote::Cs1)
-        } else if s.starts_with("d1") {
-            Ok(MidiNote::D1)
-        } else if s.starts_with("ds1") {
-            Ok(MidiNote::Ds1)
-        } else if s.starts_with("e1") {
-            Ok(MidiNote::E1)
-        } else if s.starts_with("f1") {
-            Ok(MidiNote::F1)
-        } else if s.starts_with("fs1") {
-            Ok(MidiNote::Fs1)
-        } else if s.starts_with("g1") {
-            Ok(MidiNote::G1)
-        } else if s.starts_with("gs1") {
-            Ok(MidiNote::Gs1)
-        } else if s.starts_with("a1") {
-            Ok(MidiNote::A1)
-        } else if s.starts_with("as1") {
-            Ok(MidiNote::As1)
-        } else if s.starts_with("b1") {
-            Ok(MidiNote::B1)
-        } else if s.starts_with("c2") {
-            Ok(MidiNote::C2)
-        } else if s.starts_with("cs2") {
-            Ok(MidiNote::Cs2)
-        } else if s.starts_with("d2") {
-            Ok(MidiNote::D2)
-        } else if s.starts_with("ds2") {
-            Ok(MidiNote::Ds2)
-        } else if s.starts_with("e2") {
-            Ok(MidiNote::E2)
-        } else if s.starts_with("f2") {
-            Ok(MidiNote::F2)
-        } else if s.starts_with("fs2") {
-            Ok(MidiNote::Fs2)
-        } else if s.starts_with("g2") {
-            Ok(MidiNote::G2)
-        } else if s.starts_with("gs2") {
-            Ok(MidiNote::Gs2)
-        } else if s.starts_with("a2") {
-            Ok(MidiNote::A2)
-        } else if s.starts_with("as2") {
-            Ok(MidiNote::As2)
-        } else if s.starts_with("b2") {
-            Ok(MidiNote::B2)
-        } else if s.starts_with("c3") {
-            Ok(MidiNote::C3)
-        } else if s.starts_with("cs3") {
-            Ok(MidiNote::Cs3)
-        } else if s.starts_with("d3") {
-            Ok(MidiNote::D3)
-        } else if s.starts_with("ds3") {
-            Ok(MidiNote::Ds3)
-        } else if s.starts_with("e3") {
-            Ok(MidiNote::E3)
-        } else if s.starts_with("f3") {
-            Ok(MidiNote::F3)
-        } else if s.starts_with("fs3") {
-            Ok(MidiNote::Fs3)
-        } else if s.starts_with("g3") {
-            Ok(MidiNote::G3)
-        } else if s.starts_with("gs3") {
-            Ok(MidiNote::Gs3)
-        } else if s.starts_with("a3") {
-            Ok(MidiNote::A3)
-        } else if s.starts_with("as3") {
-            Ok(MidiNote::As3)
-        } else if s.starts_with("b3") {
-            Ok(MidiNote::B3)
-        } else if s.starts_with("c4") {
-            Ok(MidiNote::C4)
-        } else if s.starts_with("cs4") {
-            Ok(MidiNote::Cs4)
-        } else if s.starts_with("d4") {
-            Ok(MidiNote::D4)
-        } else if s.starts_with("ds4") {
-            Ok(MidiNote::Ds4)
-        } else if s.starts_with("e4") {
-            Ok(MidiNote::E4)
-        } else if s.starts_with("f4") {
-            Ok(MidiNote::F4)
-        } else if s.starts_with("fs4") {
-            Ok(MidiNote::Fs4)
-        } else if s.starts_with("g4") {
-            Ok(MidiNote::G4)
-        } else if s.starts_with("gs4") {
-            Ok(MidiNote::Gs4)
-        } else if s.starts_with("a4") {
-            Ok(MidiNote::A4)
-        } else if s.starts_with("as4") {
-            Ok(MidiNote::As4)
-        } else if s.starts_with("b4") {
-            Ok(MidiNote::B4)
-        } else if s.starts_with("c5") {
-            Ok(MidiNote::C5)
-        } else if s.starts_with("cs5") {
-            Ok(MidiNote::Cs5)
-        } else if s.starts_with("d5") {
-            Ok(MidiNote::D5)
-        } else if s.starts_with("ds5") {
-            Ok(MidiNote::Ds5)
-        } else if s.starts_with("e5") {
-            Ok(MidiNote::E5)
-        } else if s.starts_with("f5") {
-            Ok(MidiNote::F5)
-        } else if s.starts_with("fs5") {
-            Ok(MidiNote::Fs5)
-        } else if s.starts_with("g5") {
-            Ok(MidiNote::G5)
-        } else if s.starts_with("gs5") {
-            Ok(MidiNote::Gs5)
-        } else if s.starts_with("a5") {
-            Ok(MidiNote::A5)
-        } else if s.starts_with("as5") {
-            Ok(MidiNote::As5)
-        } else if s.starts_with("b5") {
-            Ok(MidiNote::B5)
-        } else if s.starts_with("c6") {
-            Ok(MidiNote::C6)
-        } else if s.starts_with("cs6") {
-            Ok(MidiNote::Cs6)
-        } else if s.starts_with("d6") {
-            Ok(MidiNote::D6)
-        } else if s.starts_with("ds6") {
-            Ok(MidiNote::Ds6)
-        } else if s.starts_with("e6") {
-            Ok(MidiNote::E6)
-        } else if s.starts_with("f6") {
-            Ok(MidiNote::F6)
-        } else if s.starts_with("fs6") {
-            Ok(MidiNote::Fs6)
-        } else if s.starts_with("g6") {
-            Ok(MidiNote::G6)
-        } else if s.starts_with("gs6") {
-            Ok(MidiNote::Gs6)
-        } else if s.starts_with("a6") {
-            Ok(MidiNote::A6)
-        } else if s.starts_with("as6") {
-            Ok(MidiNote::As6)
-        } else if s.starts_with("b6") {
-            Ok(MidiNote::B6)
-        } else if s.starts_with("c7") {
-            Ok(MidiNote::C7)
-        } else if s.starts_with("cs7") {
-            Ok(MidiNote::Cs7)
-        } else if s.starts_with("d7") {
-            Ok(MidiNote::D7)
-        } else if s.starts_with("ds7") {
-            Ok(MidiNote::Ds7)
-        } else if s.starts_with("e7") {
-            Ok(MidiNote::E7)
-        } else if s.starts_with("f7") {
-            Ok(MidiNote::F7)
-        } else if s.starts_with("fs7") {
-            Ok(MidiNote::Fs7)
-        } else if s.starts_with("g7") {
-            Ok(MidiNote::G7)
-        } else if s.starts_with("gs7") {
-            Ok(MidiNote::Gs7)
-        } else if s.starts_with("a7") {
-            Ok(MidiNote::A7)
-        } else if s.starts_with("as7") {
-            Ok(MidiNote::As7)
-        } else if s.starts_with("b7") {
-            Ok(MidiNote::B7)
-        } else if s.starts_with("c8") {
-            Ok(MidiNote::C8)
-        } else if s.starts_with("cs8") {
-            Ok(MidiNote::Cs8)
-        } else if s.starts_with("d8") {
-            Ok(MidiNote::D8)
-        } else if s.starts_with("ds8") {
-            Ok(MidiNote::Ds8)
-        } else if s.starts_with("e8") {
-            Ok(MidiNote::E8)
-        } else if s.starts_with("f8") {
-            Ok(MidiNote::F8)
-        } else if s.starts_with("fs8") {
-            Ok(MidiNote::Fs8)
-        } else if s.starts_with("g8") {
-            Ok(MidiNote::G8)
-        } else if s.starts_with("gs8") {
-            Ok(MidiNote::Gs8)
-        } else if s.starts_with("a8") {
-            Ok(MidiNote::A8)
-        } else if s.starts_with("as8") {
-            Ok(MidiNote::As8)
-        } else if s.starts_with("b8") {
-            Ok(MidiNote::B8)
-        } else if s.starts_with("c9") {
-            Ok(MidiNote::C9)
-        } else if s.starts_with("cs9") {
-            Ok(MidiNote::Cs9)
-        } else if s.starts_with("d9") {
-            Ok(MidiNote::D9)
-        } else if s.starts_with("ds9") {
-            Ok(MidiNote::Ds9)
-        } else if s.starts_with("e9") {
-            Ok(MidiNote::E9)
-        } else if s.starts_with("f9") {
-            Ok(MidiNote::F9)
-        } else if s.starts_with("fs9") {
-            Ok(MidiNote::Fs9)
-        } else if s.starts_with("g9") {
-            Ok(MidiNote::G9)
+    pub fn index(self) -> u8 {
+        self as u8
+    }
+
+    /// Parse a note name using a particular octave convention.
+    ///
+    /// Accepts a plain note number ("60"), or a note letter followed by an
+    /// optional sharp ("#" or "s") or flat ("b") and an octave number
+    /// ("C4", "C#4", "Cs4", "Db4", "C-1").  The octave "n" means the lowest
+    /// octave, as in the `MidiNote` variant names ("Csn").
+    pub fn parse_with(txt: &str, convention: OctaveConvention) -> Result<MidiNote, MidiError> {
+        let txt = txt.trim();
+        if !txt.is_empty() && txt.chars().all(|c| c.is_ascii_digit()) {
+            return match txt.parse::<u8>() {
+                Ok(val) => MidiNote::new(val),
+                Err(_) => Err(MidiError::NoteOutOfRange),
+            };
+        }
+
+        let mut chars = txt.chars();
+        let mut semitone: i32 = match chars.next().map(|c| c.to_ascii_lowercase()) {
+            Some('c') => 0,
+            Some('d') => 2,
+            Some('e') => 4,
+            Some('f') => 5,
+            Some('g') => 7,
+            Some('a') => 9,
+            Some('b') => 11,
+            _ => return Err(MidiError::Unparseable),
+        };
+
+        let mut rest = chars.as_str();
+        if let Some(stripped) = rest.strip_prefix(|c| c == '#' || c == 's' || c == 'S') {
+            semitone += 1;
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix('b') {
+            semitone -= 1;
+            rest = stripped;
+        }
+
+        let lowest = convention.lowest_octave();
+        let octave = if rest.eq_ignore_ascii_case("n") {
+            lowest
         } else {
-            Err(MidiError::Unparseable)
+            rest.parse::<i32>().map_err(|_| MidiError::Unparseable)?
+        };
+
+        let index = (octave - lowest) * 12 + semitone;
+        if !(0..=127).contains(&index) {
+            return Err(MidiError::NoteOutOfRange);
         }
+        MidiNote::new(index as u8)
     }
+}
 
-    pub fn index(self) -> u8 {
-        self as u8
+impl MidiNote {
+    /// The note name using a particular octave convention, e.g. "C#4"
+    pub fn name_with(self, convention: OctaveConvention) -> String {
+        let index = i32::from(self.index());
+        let octave = index / 12 + convention.lowest_octave();
+        format!("{}{}", NOTE_NAMES[(index % 12) as usize], octave)
+    }
+}
+
+impl FromStr for MidiNote {
+    type Err = MidiError;
+
+    /// Parse a note name using the current octave convention.
+    fn from_str(s: &str) -> Result<MidiNote, MidiError> {
+        MidiNote::parse_with(s, octave_convention())
+    }
+}
+
+impl fmt::Display for MidiNote {
+    /// Write the note name using the current octave convention, e.g. "C#4"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name_with(octave_convention()))
     }
 }
 
 impl MidiMessage {
     pub fn new(message: &[u8]) -> Result<MidiMessage, MidiError> {
-        match message[0] & 0xf0 {
-            0x80 => {
-                if message.len() < 3 {
-                    Err(MidiError::TooShort)
-                } else {
-                    Ok(MidiMessage {
-                        event: MidiEvent::NoteOff,
-                        channel: message[0] & 0x0f,
-                        note: MidiNote::new(message[1] & 0x7f)?,
-                        velocity: message[2] & 0x7f,
-                    })
-                }
+        let status = match message.first() {
+            Some(status) => *status,
+            None => return Err(MidiError::TooShort),
+        };
+        let (event, len) = match status & 0xf0 {
+            0x80 => (MidiEvent::NoteOff, 3),
+            0x90 => (MidiEvent::NoteOn, 3),
+            0xa0 => (MidiEvent::PolyAftertouch, 3),
+            0xb0 => (MidiEvent::ControlChange, 3),
+            0xc0 => (MidiEvent::ProgramChange, 2),
+            0xd0 => (MidiEvent::ChannelPressure, 2),
+            0xe0 => (MidiEvent::PitchBend, 3),
+            _ => return Err(MidiError::Unimplemented(status)),
+        };
+        if message.len() < len {
+            return Err(MidiError::TooShort);
+        }
+
+        let data1 = message[1] & 0x7f;
+        let data2 = if len > 2 { message[2] & 0x7f } else { 0 };
+
+        // A NoteOn with a velocity of 0 is the same as a NoteOff
+        let event = if event == MidiEvent::NoteOn && data2 == 0 {
+            MidiEvent::NoteOff
+        } else {
+            event
+        };
+
+        Ok(MidiMessage {
+            event,
+            channel: status & 0x0f,
+            data1,
+            data2,
+        })
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn event(&self) -> &MidiEvent {
+        &self.event
+    }
+
+    /// The note this message refers to, for NoteOn, NoteOff and PolyAftertouch.
+    pub fn note(&self) -> Option<MidiNote> {
+        match self.event {
+            MidiEvent::NoteOn | MidiEvent::NoteOff | MidiEvent::PolyAftertouch => {
+                MidiNote::new(self.data1).ok()
             }
-            0x90 => {
-                if message.len() < 3 {
-                    Err(MidiError::TooShort)
-                } else {
-                    let velocity = message[2] & 0x7f;
-                    let event = if velocity != 0 {
-                        MidiEvent::NoteOn
-                    } else {
-                        MidiEvent::NoteOff
-                    };
-                    Ok(MidiMessage {
-                        event,
-                        channel: message[0] & 0x0f,
-                        note: MidiNote::new(message[1] & 0x7f)?,
-                        velocity,
-                    })
+            _ => None,
+        }
+    }
+
+    /// The note velocity, for NoteOn and NoteOff.
+    pub fn velocity(&self) -> Option<u8> {
+        match self.event {
+            MidiEvent::NoteOn | MidiEvent::NoteOff => Some(self.data2),
+            _ => None,
+        }
+    }
+
+    /// The pressure value, for PolyAftertouch and ChannelPressure.
+    pub fn pressure(&self) -> Option<u8> {
+        match self.event {
+            MidiEvent::PolyAftertouch => Some(self.data2),
+            MidiEvent::ChannelPressure => Some(self.data1),
+            _ => None,
+        }
+    }
+
+    /// The controller number and value, for ControlChange.
+    pub fn control(&self) -> Option<(u8, u8)> {
+        match self.event {
+            MidiEvent::ControlChange => Some((self.data1, self.data2)),
+            _ => None,
+        }
+    }
+
+    /// The channel mode message, for a ControlChange on controllers 120-127.
+    pub fn channel_mode(&self) -> Option<ChannelMode> {
+        let (control, value) = self.control()?;
+        Some(match control {
+            120 => ChannelMode::AllSoundOff,
+            121 => ChannelMode::ResetAllControllers,
+            122 => ChannelMode::LocalControl(value >= 64),
+            123 => ChannelMode::AllNotesOff,
+            124 => ChannelMode::OmniOff,
+            125 => ChannelMode::OmniOn,
+            126 => ChannelMode::MonoOn(value),
+            127 => ChannelMode::PolyOn,
+            _ => return None,
+        })
+    }
+
+    /// The new program number, for ProgramChange.
+    pub fn program(&self) -> Option<u8> {
+        match self.event {
+            MidiEvent::ProgramChange => Some(self.data1),
+            _ => None,
+        }
+    }
+
+    /// The 14-bit bend amount, for PitchBend.  `PITCH_BEND_CENTER` means no bend.
+    pub fn pitch_bend(&self) -> Option<u16> {
+        match self.event {
+            MidiEvent::PitchBend => Some(u16::from(self.data2) << 7 | u16::from(self.data1)),
+            _ => None,
+        }
+    }
+}
+
+/// Turns an unframed stream of MIDI bytes, such as one read from a serial
+/// port or a raw MIDI device, into channel messages.
+///
+/// Running status is supported, realtime bytes (0xf8-0xff) may appear
+/// anywhere without disturbing a message in progress, and SysEx blocks and
+/// other system common messages are skipped.
+#[derive(Default)]
+pub struct MidiStreamParser {
+    /// The status byte of the current channel message, if any
+    running_status: Option<u8>,
+
+    /// Data bytes collected for the current message
+    data: Vec<u8>,
+
+    /// Whether we're in the middle of a SysEx block
+    in_sysex: bool,
+
+    /// Data bytes still to skip for a system common message
+    skip: usize,
+}
+
+impl MidiStreamParser {
+    pub fn new() -> MidiStreamParser {
+        MidiStreamParser::default()
+    }
+
+    /// Feed a chunk of bytes into the parser, returning every message that
+    /// was completed by it.  Partial messages are kept until the next call.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<MidiMessage> {
+        let mut messages = vec![];
+        for &byte in bytes {
+            if let Some(msg) = self.push(byte) {
+                messages.push(msg);
+            }
+        }
+        messages
+    }
+
+    /// Feed a single byte into the parser.
+    pub fn push(&mut self, byte: u8) -> Option<MidiMessage> {
+        // Realtime messages are a single byte and may be interleaved with anything.
+        if byte >= 0xf8 {
+            return None;
+        }
+
+        if byte & 0x80 != 0 {
+            // A new status byte terminates any SysEx block or partial message
+            self.in_sysex = false;
+            self.skip = 0;
+            self.data.clear();
+            match byte {
+                0xf0 => {
+                    self.in_sysex = true;
+                    self.running_status = None;
+                }
+                0xf1 | 0xf3 => {
+                    self.skip = 1;
+                    self.running_status = None;
                 }
+                0xf2 => {
+                    self.skip = 2;
+                    self.running_status = None;
+                }
+                0xf4..=0xf7 => self.running_status = None,
+                _ => self.running_status = Some(byte),
             }
-            _ => Err(MidiError::Unimplemented(message[0])),
+            return None;
+        }
+
+        if self.in_sysex {
+            return None;
+        }
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
         }
+
+        let status = self.running_status?;
+        self.data.push(byte);
+        let len = match status & 0xf0 {
+            0xc0 | 0xd0 => 1,
+            _ => 2,
+        };
+        if self.data.len() < len {
+            return None;
+        }
+
+        let mut raw = [status, 0, 0];
+        raw[1..=len].copy_from_slice(&self.data);
+        self.data.clear();
+        MidiMessage::new(&raw[..=len]).ok()
     }
+}
 
-    pub fn channel(&self) -> u8 {
-        self.channel
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Vec<(MidiEvent, u8, u8)> {
+        MidiStreamParser::new()
+            .feed(bytes)
+            .iter()
+            .map(|msg| (*msg.event(), msg.data1, msg.data2))
+            .collect()
     }
 
-    pub fn note(&self) -> &MidiNote {
-        &self.note
+    #[test]
+    fn running_status() {
+        assert_eq!(
+            parse(&[0x90, 60, 64, 62, 65, 0x80, 60, 0, 62, 0]),
+            [
+                (MidiEvent::NoteOn, 60, 64),
+                (MidiEvent::NoteOn, 62, 65),
+                (MidiEvent::NoteOff, 60, 0),
+                (MidiEvent::NoteOff, 62, 0),
+            ]
+        );
     }
 
-    pub fn event(&self) -> &MidiEvent {
-        &self.event
+    #[test]
+    fn realtime_inside_a_message() {
+        assert_eq!(
+            parse(&[0x90, 0xf8, 60, 0xfe, 64, 0xfa, 62, 0xfc, 65]),
+            [(MidiEvent::NoteOn, 60, 64), (MidiEvent::NoteOn, 62, 65)]
+        );
+    }
+
+    #[test]
+    fn sysex_is_skipped() {
+        // Data inside the SysEx block isn't taken as notes, and the block
+        // ends the running status
+        assert_eq!(
+            parse(&[0x90, 60, 64, 0xf0, 62, 64, 0xf8, 0xf7, 62, 64, 0x90, 64, 64]),
+            [(MidiEvent::NoteOn, 60, 64), (MidiEvent::NoteOn, 64, 64)]
+        );
+    }
+
+    #[test]
+    fn system_common_cancels_running_status() {
+        assert_eq!(
+            parse(&[0x90, 60, 64, 0xf2, 1, 2, 62, 64, 0xf1, 3, 0x90, 64, 64]),
+            [(MidiEvent::NoteOn, 60, 64), (MidiEvent::NoteOn, 64, 64)]
+        );
+
+        // A status byte also abandons a message that was partway through
+        assert_eq!(
+            parse(&[0x90, 60, 0x80, 60, 0]),
+            [(MidiEvent::NoteOff, 60, 0)]
+        );
+    }
+
+    #[test]
+    fn one_data_byte_messages() {
+        assert_eq!(
+            parse(&[0xc1, 5, 6]),
+            [
+                (MidiEvent::ProgramChange, 5, 0),
+                (MidiEvent::ProgramChange, 6, 0)
+            ]
+        );
+    }
+
+    fn note(txt: &str, convention: OctaveConvention) -> Option<u8> {
+        MidiNote::parse_with(txt, convention)
+            .ok()
+            .map(MidiNote::index)
+    }
+
+    #[test]
+    fn note_names() {
+        use OctaveConvention::{C3, C4};
+        assert_eq!(note("C4", C4), Some(60));
+        assert_eq!(note("C3", C3), Some(60));
+        assert_eq!(note("C-1", C4), Some(0));
+        assert_eq!(note("C-2", C3), Some(0));
+        assert_eq!(note("Csn", C4), Some(1));
+        assert_eq!(note("C#4", C4), Some(61));
+        assert_eq!(note("Cs4", C4), Some(61));
+        assert_eq!(note("Db4", C4), Some(61));
+        assert_eq!(note("60", C4), Some(60));
+        assert_eq!(note("60", C3), Some(60));
+        assert_eq!(note("G9", C4), Some(127));
+        assert_eq!(note("G#9", C4), None);
+        assert_eq!(note("128", C4), None);
+        assert_eq!(note("c4xyz", C4), None);
+        assert_eq!(note("H4", C4), None);
+        assert_eq!(note("", C4), None);
+
+        let c4 = MidiNote::new(60).unwrap();
+        assert_eq!(c4.name_with(C4), "C4");
+        assert_eq!(c4.name_with(C3), "C3");
+        let lowest = MidiNote::new(1).unwrap();
+        assert_eq!(lowest.name_with(C4), "C#-1");
+        assert_eq!(lowest.name_with(C3), "C#-2");
+        for index in 0..=127 {
+            let note = MidiNote::new(index).unwrap();
+            for &convention in &[C4, C3] {
+                assert_eq!(
+                    MidiNote::parse_with(&note.name_with(convention), convention).unwrap(),
+                    note
+                );
+            }
+        }
     }
 }
@@ -1,7 +1,16 @@
-use crate::midi::MidiNote;
+use crate::midi::{MidiEvent, MidiMessage, MidiNote};
 use enigo::Key;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Result};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Result};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+
+mod profile;
 
 /// Proxy for Enigo::Key, since that variant isn't cloneable
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -112,21 +121,300 @@ impl KbdKey {
     }
 }
 
+/// Names for the keys that aren't a single character, as used in mapping files
+const KEY_NAMES: [(&str, KbdKey); 30] = [
+    ("return", KbdKey::Return),
+    ("tab", KbdKey::Tab),
+    ("space", KbdKey::Space),
+    ("backspace", KbdKey::Backspace),
+    ("escape", KbdKey::Escape),
+    ("meta", KbdKey::Meta),
+    ("shift", KbdKey::Shift),
+    ("capslock", KbdKey::CapsLock),
+    ("alt", KbdKey::Alt),
+    ("option", KbdKey::Option),
+    ("ctrl", KbdKey::Control),
+    ("home", KbdKey::Home),
+    ("pageup", KbdKey::PageUp),
+    ("pagedown", KbdKey::PageDown),
+    ("left", KbdKey::LeftArrow),
+    ("right", KbdKey::RightArrow),
+    ("down", KbdKey::DownArrow),
+    ("up", KbdKey::UpArrow),
+    ("f1", KbdKey::F1),
+    ("f2", KbdKey::F2),
+    ("f3", KbdKey::F3),
+    ("f4", KbdKey::F4),
+    ("f5", KbdKey::F5),
+    ("f6", KbdKey::F6),
+    ("f7", KbdKey::F7),
+    ("f8", KbdKey::F8),
+    ("f9", KbdKey::F9),
+    ("f10", KbdKey::F10),
+    ("f11", KbdKey::F11),
+    ("f12", KbdKey::F12),
+];
+
+/// Other names that are accepted when parsing
+const KEY_ALIASES: [(&str, KbdKey); 5] = [
+    ("control", KbdKey::Control),
+    ("enter", KbdKey::Return),
+    ("esc", KbdKey::Escape),
+    ("super", KbdKey::Meta),
+    ("win", KbdKey::Meta),
+];
+
+impl FromStr for KbdKey {
+    type Err = String;
+
+    /// Parse a key name such as "ctrl" or "f5", a single character such as
+    /// "q", or a raw keycode such as "0x38".  Names are case-insensitive.
+    /// A character may also be quoted, as in "' '", for whitespace that
+    /// would otherwise be trimmed away.
+    fn from_str(s: &str) -> std::result::Result<KbdKey, String> {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next(), chars.next(), chars.next()) {
+            (Some(c), None, _, _) => return Ok(KbdKey::Layout(c)),
+            (Some('\''), Some(c), Some('\''), None) => return Ok(KbdKey::Layout(c)),
+            _ => (),
+        }
+        if let Some(hex) = s.strip_prefix("0x") {
+            return u16::from_str_radix(hex, 16)
+                .map(KbdKey::Raw)
+                .map_err(|_| format!("invalid keycode: {}", s));
+        }
+        let lower = s.to_lowercase();
+        KEY_NAMES
+            .iter()
+            .chain(KEY_ALIASES.iter())
+            .find(|(name, _)| *name == lower)
+            .map(|(_, key)| key.clone())
+            .ok_or_else(|| format!("unknown key: {}", s))
+    }
+}
+
+impl fmt::Display for KbdKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KbdKey::Layout(c) if c.is_whitespace() => write!(f, "'{}'", c),
+            KbdKey::Layout(c) => write!(f, "{}", c),
+            KbdKey::Raw(code) => write!(f, "0x{:x}", code),
+            ref key => {
+                let (name, _) = KEY_NAMES.iter().find(|(_, k)| k == key).unwrap();
+                write!(f, "{}", name)
+            }
+        }
+    }
+}
+
+/// Parse a set of keys joined with "+", such as "ctrl+shift".  "none" is
+/// the empty set.
+pub fn parse_modifiers(txt: &str) -> std::result::Result<Vec<KbdKey>, String> {
+    if txt == "none" {
+        return Ok(vec![]);
+    }
+    txt.split('+').map(str::parse).collect()
+}
+
+/// Whether two sets of modifiers hold the same keys, in any order
+fn same_modifiers(first: &[KbdKey], second: &[KbdKey]) -> bool {
+    first.len() == second.len() && first.iter().all(|key| second.contains(key))
+}
+
+/// The kinds of pause that sequences make while keys register.  How long
+/// each lasts depends on the machine and the game, so it comes from `Timing`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Settle {
+    /// Time for a keyboard modifier to stick
+    Modifier,
+    /// Time for a keydown event to stick
+    Key,
+    /// Time required for system events, such as Esc
+    System,
+    /// A small delay required when switching between octaves
+    Octave,
+}
+
+/// The name of each kind of settle time, and how long it lasts by default
+const SETTLES: [(&str, Settle, u64); 4] = [
+    ("mod", Settle::Modifier, 150),
+    ("key", Settle::Key, 40),
+    ("sys", Settle::System, 400),
+    ("octave", Settle::Octave, 10),
+];
+
+impl FromStr for Settle {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Settle, String> {
+        SETTLES
+            .iter()
+            .find(|(name, _, _)| *name == s)
+            .map(|&(_, settle, _)| settle)
+            .ok_or_else(|| format!("unknown delay: {}", s))
+    }
+}
+
+impl fmt::Display for Settle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", SETTLES[*self as usize].0)
+    }
+}
+
+/// How long each kind of `Settle` lasts, in milliseconds.  Any that aren't
+/// set fall back to another `Timing`, and finally to the built-in defaults.
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct Timing {
+    ms: [Option<u64>; 4],
+}
+
+impl Timing {
+    pub fn new() -> Timing {
+        Timing::default()
+    }
+
+    pub fn get(&self, settle: Settle) -> Option<u64> {
+        self.ms[settle as usize]
+    }
+
+    pub fn set(&mut self, settle: Settle, msecs: u64) {
+        self.ms[settle as usize] = Some(msecs);
+    }
+
+    /// How long to wait for `settle`, using the default if it isn't set
+    pub fn delay(&self, settle: Settle) -> u64 {
+        self.get(settle).unwrap_or(SETTLES[settle as usize].2)
+    }
+
+    /// These timings, with any gaps filled in from `fallback`
+    pub fn or(&self, fallback: &Timing) -> Timing {
+        let mut timing = *fallback;
+        for (idx, msecs) in self.ms.iter().enumerate() {
+            if msecs.is_some() {
+                timing.ms[idx] = *msecs;
+            }
+        }
+        timing
+    }
+
+    /// Apply a setting of the form "key=40"
+    pub fn parse_setting(&mut self, txt: &str) -> std::result::Result<(), String> {
+        let (name, msecs) = txt
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=MS: {}", txt))?;
+        let msecs = msecs
+            .parse()
+            .map_err(|_| format!("invalid delay: {}", msecs))?;
+        self.set(name.parse()?, msecs);
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Event {
     /// Insert a Delay for a specified number of ms
     Delay(u64),
 
+    /// Wait for keys to register, for as long as the profile says
+    Settle(Settle),
+
     /// Press a key
     KeyDown(KbdKey),
 
     /// Release a key
     KeyUp(KbdKey),
 
-    /// Keep a key held down during this script.
-    /// Note that the key may be continued to be held down until a script
-    /// with no NoteMod is encountered.
-    NoteMod(Option<KbdKey>),
+    /// Hold exactly these modifiers during this script, releasing any other
+    /// of the profile's state modifiers.
+    /// Note that the keys may be continued to be held down until a script
+    /// with a different NoteMod is encountered.
+    NoteMod(Vec<KbdKey>),
+
+    /// Move the game to this octave, relative to the one it starts in, by
+    /// tapping the profile's octave keys.  Does nothing if the profile has
+    /// no octave keys.
+    Octave(i8),
+}
+
+impl FromStr for Event {
+    type Err = String;
+
+    /// Parse an event as written in a profile, e.g. "down f5", "up ctrl",
+    /// "delay 40", "settle key", "mod ctrl+shift", "mod none" or "octave -1"
+    fn from_str(s: &str) -> std::result::Result<Event, String> {
+        let (kind, arg) = s
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| format!("event needs an argument: {}", s))?;
+        let arg = arg.trim();
+        match kind {
+            "down" => Ok(Event::KeyDown(arg.parse()?)),
+            "up" => Ok(Event::KeyUp(arg.parse()?)),
+            "delay" => arg
+                .parse()
+                .map(Event::Delay)
+                .map_err(|_| format!("invalid delay: {}", arg)),
+            "settle" => Ok(Event::Settle(arg.parse()?)),
+            "mod" => Ok(Event::NoteMod(parse_modifiers(arg)?)),
+            "octave" => arg
+                .parse()
+                .map(Event::Octave)
+                .map_err(|_| format!("invalid octave: {}", arg)),
+            _ => Err(format!("unknown event: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Event::Delay(msecs) => write!(f, "delay {}", msecs),
+            Event::Settle(settle) => write!(f, "settle {}", settle),
+            Event::KeyDown(ref key) => write!(f, "down {}", key),
+            Event::KeyUp(ref key) => write!(f, "up {}", key),
+            Event::NoteMod(ref keys) if keys.is_empty() => write!(f, "mod none"),
+            Event::NoteMod(ref keys) => {
+                let names: Vec<String> = keys.iter().map(KbdKey::to_string).collect();
+                write!(f, "mod {}", names.join("+"))
+            }
+            Event::Octave(octave) => write!(f, "octave {}", octave),
+        }
+    }
+}
+
+/// What to do with a NoteOn sequence that is still running when its note is
+/// released or retriggered
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ReleasePolicy {
+    /// Let the sequence finish, then run the next one
+    Finish,
+    /// Run the rest of the sequence immediately, without its delays
+    Truncate,
+    /// Abandon the rest of the sequence, releasing any keys it pressed
+    /// and hasn't released yet
+    Cancel,
+}
+
+impl FromStr for ReleasePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<ReleasePolicy, String> {
+        match s {
+            "finish" => Ok(ReleasePolicy::Finish),
+            "truncate" => Ok(ReleasePolicy::Truncate),
+            "cancel" => Ok(ReleasePolicy::Cancel),
+            _ => Err(format!("unknown release policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ReleasePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            ReleasePolicy::Finish => "finish",
+            ReleasePolicy::Truncate => "truncate",
+            ReleasePolicy::Cancel => "cancel",
+        };
+        write!(f, "{}", name)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -137,9 +425,24 @@ pub struct NoteMapping {
     /// The source channel.  0 is a good default here.
     channel: u8,
 
-    /// The name of the instrument that we're looking for.
+    /// The name of the instrument that we're looking for.  This is matched
+    /// against the MIDI device name, and `None` matches any device.
     instrument_name: Option<String>,
 
+    /// The range of NoteOn velocities this mapping responds to.  Several
+    /// mappings for the same note may cover different ranges.
+    pub velocity: RangeInclusive<u8>,
+
+    /// What happens to a NoteOn sequence that is still running when the
+    /// note is released or pressed again.
+    pub release: ReleasePolicy,
+
+    /// How long keys pressed by this mapping may be held down
+    pub hold: HoldLimit,
+
+    /// Settle times for this mapping that differ from the profile's
+    pub timing: Timing,
+
     /// A sequence to call when the note is pressed.
     pub on: Vec<Event>,
 
@@ -153,44 +456,404 @@ impl NoteMapping {
             note,
             channel,
             instrument_name,
+            velocity: 0..=127,
+            release: ReleasePolicy::Finish,
+            hold: HoldLimit::Default,
+            timing: Timing::new(),
             on: vec![],
             off: vec![],
         }
     }
 
-    pub fn down_event(key: char, modifier: Option<KbdKey>, _delay: Option<u64>) -> Vec<Event> {
-        let mut v = vec![];
+    /// The keys left held by the `on` sequence, other than modifiers, along
+    /// with the modifiers that are held at the time they're pressed.  Keys
+    /// that the sequence releases itself are tapped, and aren't included.
+    fn pressed_keys(&self) -> Vec<(KbdKey, &[KbdKey])> {
+        let mut keys: Vec<(KbdKey, &[KbdKey])> = vec![];
+        let mut modifiers: &[KbdKey] = &[];
+        for event in &self.on {
+            match event {
+                Event::NoteMod(m) => modifiers = m,
+                Event::KeyDown(key) => keys.push((key.clone(), modifiers)),
+                Event::KeyUp(key) => keys.retain(|(held, _)| held != key),
+                _ => (),
+            }
+        }
+        keys
+    }
 
-        if let Some(ref m) = modifier {
-            v.push(Event::NoteMod(Some(m.clone())));
-        } else {
-            v.push(Event::NoteMod(None));
+    pub fn down_event(key: char, modifiers: &[KbdKey]) -> Vec<Event> {
+        vec![
+            Event::NoteMod(modifiers.to_vec()),
+            Event::KeyDown(KbdKey::Layout(key)),
+        ]
+    }
+
+    /// The modifiers that the `on` sequence holds, if any
+    pub fn modifiers(&self) -> &[KbdKey] {
+        self.on
+            .iter()
+            .find_map(|event| match event {
+                Event::NoteMod(m) => Some(m.as_slice()),
+                _ => None,
+            })
+            .unwrap_or(&[])
+    }
+
+    pub fn up_event(key: char) -> Vec<Event> {
+        vec![Event::KeyUp(KbdKey::Layout(key))]
+    }
+}
+
+impl fmt::Display for NoteMapping {
+    /// Describe what triggers this mapping, e.g. "C4 on channel 0"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on channel {}", self.note, self.channel)?;
+        if self.velocity != (0..=127) {
+            write!(
+                f,
+                " (velocity {}-{})",
+                self.velocity.start(),
+                self.velocity.end()
+            )?;
         }
+        if let Some(ref name) = self.instrument_name {
+            write!(f, " for \"{}\"", name)?;
+        }
+        Ok(())
+    }
+}
 
-        v.push(Event::KeyDown(KbdKey::Layout(key)));
+/// Split a line of a mappings file into whitespace-separated fields, each
+/// with its column (counting from 1).  A field starting with "#" begins a
+/// comment, unless it's the key of a mapping, so that "#" can still be mapped.
+fn split_fields(line: &str) -> Vec<(usize, &str)> {
+    let mut fields = vec![];
+    let mut start = None;
+    for (idx, c) in line.char_indices().chain(Some((line.len(), ' '))) {
+        match (start, c.is_whitespace()) {
+            (None, false) => start = Some(idx),
+            (Some(begin), true) => {
+                fields.push((line[..begin].chars().count() + 1, &line[begin..idx]));
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    let is_mapping = fields
+        .first()
+        .is_some_and(|&(_, field)| !["modifiers", "timing", "octave", "panic"].contains(&field));
+    let comment = fields.iter().enumerate().position(|(idx, &(_, field))| {
+        field.starts_with('#') && !(is_mapping && (idx == 2 || idx == 3))
+    });
+    if let Some(comment) = comment {
+        fields.truncate(comment);
+    }
+    fields
+}
 
-        v
+/// A line with any comment removed, for finding `[device name]` lines.  As
+/// in `split_fields`, a comment starts with a "#" at the start of a field.
+fn strip_comment(line: &str) -> &str {
+    let comment = line.char_indices().find(|&(idx, c)| {
+        c == '#'
+            && line[..idx]
+                .chars()
+                .next_back()
+                .is_none_or(char::is_whitespace)
+    });
+    match comment {
+        Some((idx, _)) => &line[..idx],
+        None => line,
     }
+}
 
-    pub fn up_event(key: char, _modifier: Option<KbdKey>, _delay: Option<u64>) -> Vec<Event> {
-        /*
-        let mut v = vec![];
-                if let Some(ref m) = modifier {
-                    v.push(Event::KeyUp(m.clone()));
-                }
+/// The column just past the last field, for reporting something missing
+fn end_column(fields: &[(usize, &str)]) -> usize {
+    fields
+        .last()
+        .map_or(1, |&(column, field)| column + field.chars().count())
+}
 
-                if let Some(d) = delay {
-                    v.push(Event::Delay(d));
-                }
-                v.push(Event::KeyUp(KbdKey::Layout(key)));
-        */
-        vec![Event::KeyUp(KbdKey::Layout(key))]
+fn parse_channel(txt: &str) -> std::result::Result<u8, String> {
+    match txt.parse::<u8>() {
+        Ok(channel) if (channel as usize) < CHANNELS => Ok(channel),
+        _ => Err(format!("invalid channel: {}", txt)),
+    }
+}
+
+/// Parse the fields after "octave" in a mapping file: the up and down keys,
+/// then options of the form "min=N", "max=N", "settle=MS" or "wrap"
+fn parse_octave_keys(fields: &[&str]) -> std::result::Result<OctaveKeys, String> {
+    if fields.len() < 2 {
+        return Err("expected an up key and a down key".to_owned());
+    }
+    let mut octave_keys = OctaveKeys::new(fields[0].parse()?, fields[1].parse()?);
+    for option in &fields[2..] {
+        match option.split_once('=') {
+            Some(("min", min)) => {
+                octave_keys.min = min
+                    .parse()
+                    .map_err(|_| format!("invalid octave: {}", min))?
+            }
+            Some(("max", max)) => {
+                octave_keys.max = max
+                    .parse()
+                    .map_err(|_| format!("invalid octave: {}", max))?
+            }
+            Some(("settle", ms)) => {
+                octave_keys.settle_ms =
+                    Some(ms.parse().map_err(|_| format!("invalid delay: {}", ms))?)
+            }
+            None if *option == "wrap" => octave_keys.wrap = true,
+            _ => return Err(format!("unknown option: {}", option)),
+        }
+    }
+    if octave_keys.min > 0 || octave_keys.max < 0 {
+        return Err("the octave range must include the starting octave 0".to_owned());
+    }
+    Ok(octave_keys)
+}
+
+/// Parse a velocity range such as "0-63", or a single velocity such as "127"
+fn parse_velocity_range(txt: &str) -> Option<RangeInclusive<u8>> {
+    let (lo, hi) = match txt.split_once('-') {
+        Some((lo, hi)) => (lo.parse::<u8>().ok()?, hi.parse::<u8>().ok()?),
+        None => {
+            let v = txt.parse::<u8>().ok()?;
+            (v, v)
+        }
+    };
+    if lo > hi || hi > 127 {
+        return None;
+    }
+    Some(lo..=hi)
+}
+
+/// The number of MIDI channels, each of which has a full set of notes
+const CHANNELS: usize = 16;
+
+/// The number of notes on each channel
+const NOTES: usize = 128;
+
+/// Mappings for one device (or for any device), indexed directly by
+/// channel and note.  Each slot holds the velocity layers for that note, in
+/// the order they were added.
+struct MappingTable {
+    slots: Vec<Vec<Arc<NoteMapping>>>,
+}
+
+impl MappingTable {
+    fn new() -> MappingTable {
+        MappingTable {
+            slots: vec![vec![]; CHANNELS * NOTES],
+        }
+    }
+
+    fn slot(channel: u8, note: MidiNote) -> usize {
+        channel as usize * NOTES + note.index() as usize
+    }
+
+    fn find(&self, note: MidiNote, channel: u8, velocity: Option<u8>) -> Option<&Arc<NoteMapping>> {
+        self.slots[MappingTable::slot(channel, note)]
+            .iter()
+            .find(|mapping| velocity.is_none_or(|v| mapping.velocity.contains(&v)))
+    }
+}
+
+/// How long the keys pressed by a mapping may stay held before the
+/// watchdog releases them
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum HoldLimit {
+    /// Use the limit given on the command line, if any
+    Default,
+    /// Never release the keys, for pads that are meant to be held
+    Unlimited,
+    /// Release the keys after this many milliseconds
+    Millis(u64),
+}
+
+impl FromStr for HoldLimit {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<HoldLimit, String> {
+        match s {
+            "none" => Ok(HoldLimit::Unlimited),
+            _ => s
+                .parse()
+                .map(HoldLimit::Millis)
+                .map_err(|_| format!("invalid hold time: {}", s)),
+        }
+    }
+}
+
+/// The note or controller that fires a `PanicTrigger`
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Trigger {
+    Note(MidiNote),
+    Control(u8),
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+
+    /// Parse a note such as "C4" or "60", or a controller such as "cc64"
+    fn from_str(txt: &str) -> std::result::Result<Trigger, String> {
+        if let Some(cc) = txt.strip_prefix("cc").or_else(|| txt.strip_prefix("CC")) {
+            return match cc.parse::<u8>() {
+                Ok(cc) if cc < 128 => Ok(Trigger::Control(cc)),
+                _ => Err(format!("invalid controller: {}", txt)),
+            };
+        }
+        txt.parse::<MidiNote>()
+            .map(Trigger::Note)
+            .map_err(|_| format!("invalid note or controller: {}", txt))
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Trigger::Note(note) => write!(f, "{}", note),
+            Trigger::Control(cc) => write!(f, "cc{}", cc),
+        }
+    }
+}
+
+/// Keys that move the game up or down an octave, for games that use them
+/// instead of held modifiers
+#[derive(Debug, PartialEq, Clone)]
+pub struct OctaveKeys {
+    pub up: KbdKey,
+    pub down: KbdKey,
+
+    /// The lowest and highest octaves the game can reach, relative to the
+    /// one it starts in
+    pub min: i8,
+    pub max: i8,
+
+    /// Whether going up from the highest octave leads to the lowest, and
+    /// the other way around
+    pub wrap: bool,
+
+    /// How long to wait after each tap for the game to catch up, if not the
+    /// profile's key delay
+    pub settle_ms: Option<u64>,
+}
+
+impl OctaveKeys {
+    pub fn new(up: KbdKey, down: KbdKey) -> OctaveKeys {
+        OctaveKeys {
+            up,
+            down,
+            min: -1,
+            max: 1,
+            wrap: false,
+            settle_ms: None,
+        }
+    }
+
+    /// The first tap on the shortest way from `current` to `target`, along
+    /// with the octave it leads to.  `None` once there.
+    pub fn next_tap(&self, current: i8, target: i8) -> Option<(&KbdKey, i8)> {
+        let target = target.clamp(self.min, self.max);
+        if current == target {
+            return None;
+        }
+        let go_up = if self.wrap {
+            let span = i16::from(self.max) - i16::from(self.min) + 1;
+            let ups = (i16::from(target) - i16::from(current)).rem_euclid(span);
+            ups <= span - ups
+        } else {
+            target > current
+        };
+        Some(if go_up {
+            let next = if current >= self.max {
+                self.min
+            } else {
+                current + 1
+            };
+            (&self.up, next)
+        } else {
+            let next = if current <= self.min {
+                self.max
+            } else {
+                current - 1
+            };
+            (&self.down, next)
+        })
+    }
+}
+
+/// A note or controller that releases every held key and cancels every
+/// queued sequence, for getting out of a stuck state from the instrument.
+#[derive(Debug, Clone)]
+pub struct PanicTrigger {
+    pub trigger: Trigger,
+    pub channel: u8,
+    pub instrument_name: Option<String>,
+}
+
+impl PanicTrigger {
+    /// Whether a message from `device` fires this trigger.  Notes fire on
+    /// NoteOn, and controllers on any value other than 0.
+    fn matches(&self, msg: &MidiMessage, device: Option<&str>) -> bool {
+        if msg.channel() != self.channel {
+            return false;
+        }
+        if let Some(ref name) = self.instrument_name {
+            if !device.is_some_and(|device| device.contains(name.as_str())) {
+                return false;
+            }
+        }
+        match self.trigger {
+            Trigger::Note(note) => *msg.event() == MidiEvent::NoteOn && msg.note() == Some(note),
+            Trigger::Control(cc) => msg.control().is_some_and(|(c, v)| c == cc && v > 0),
+        }
     }
 }
 
-#[derive(Default)]
 pub struct NoteMappings {
-    mappings: Vec<NoteMapping>,
+    /// What to do when a mapping is added twice
+    duplicates: DuplicatePolicy,
+
+    /// Notes and controllers that release everything
+    panics: Vec<PanicTrigger>,
+
+    /// The modifiers that a NoteMod may press or release
+    modifiers: Vec<KbdKey>,
+
+    /// The keys that an Octave event taps, if the game uses them
+    octave_keys: Option<OctaveKeys>,
+
+    /// Settle times for every mapping in the profile
+    timing: Timing,
+
+    /// Mappings that apply to any device
+    generic: MappingTable,
+
+    /// Mappings for specific devices, keyed by the instrument name that
+    /// must appear in the device name
+    devices: Vec<(String, MappingTable)>,
+
+    /// The indexes in `devices` of the tables that apply to each device
+    /// name, in the order they are searched.  This is worked out the first
+    /// time a device is seen, so that notes don't compare any names.
+    device_tables: RefCell<HashMap<String, Vec<usize>>>,
+}
+
+impl Default for NoteMappings {
+    fn default() -> NoteMappings {
+        NoteMappings {
+            duplicates: DuplicatePolicy::LastWins,
+            panics: vec![],
+            modifiers: vec![KbdKey::Shift, KbdKey::Control],
+            octave_keys: None,
+            timing: Timing::new(),
+            generic: MappingTable::new(),
+            devices: vec![],
+            device_tables: Default::default(),
+        }
+    }
 }
 
 impl NoteMappings {
@@ -198,56 +861,644 @@ impl NoteMappings {
         NoteMappings::default()
     }
 
-    /// Find a mapping for a given note, if one exists
+    /// Whether a message is one of the panic triggers
+    pub fn is_panic(&self, msg: &MidiMessage, device: Option<&str>) -> bool {
+        self.panics.iter().any(|panic| panic.matches(msg, device))
+    }
+
+    /// The state modifiers for this profile: those that are switched on and
+    /// off to suit each note.  Every modifier that a mapping asks for is
+    /// included, whether or not it was listed in a "modifiers" line.
+    pub fn modifiers(&self) -> Vec<KbdKey> {
+        let mut modifiers = self.modifiers.clone();
+        for mapping in self.iter() {
+            for key in mapping.modifiers() {
+                if !modifiers.contains(key) {
+                    modifiers.push(key.clone());
+                }
+            }
+        }
+        modifiers
+    }
+
+    pub fn set_modifiers(&mut self, modifiers: Vec<KbdKey>) {
+        self.modifiers = modifiers;
+    }
+
+    pub fn octave_keys(&self) -> Option<&OctaveKeys> {
+        self.octave_keys.as_ref()
+    }
+
+    pub fn set_octave_keys(&mut self, octave_keys: Option<OctaveKeys>) {
+        self.octave_keys = octave_keys;
+    }
+
+    pub fn timing(&self) -> &Timing {
+        &self.timing
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+    }
+
+    pub fn panics(&self) -> &[PanicTrigger] {
+        &self.panics
+    }
+
+    pub fn add_panic(&mut self, panic: PanicTrigger) {
+        self.panics.push(panic);
+    }
+
+    /// Find a mapping for a given note, if one exists.  If `velocity` is
+    /// `None`, the first mapping for the note is returned regardless of its
+    /// velocity range.
+    ///
+    /// Mappings for a specific device are matched if their instrument name
+    /// appears in `device`, and are preferred over mappings for any device.
     pub fn find(
         &self,
         note: MidiNote,
         channel: u8,
-        instrument_name: Option<String>,
-    ) -> Option<NoteMapping> {
-        for mapping in &self.mappings {
-            if mapping.note == note
-                && mapping.channel == channel
-                && mapping.instrument_name == instrument_name
-            {
-                return Some(mapping.clone());
-            }
-        }
-        None
-    }
-
-    pub fn import(&mut self, filename: &str) -> Result<()> {
-        let f = File::open(filename)?;
-        let buf_reader = BufReader::new(f);
-        for line in buf_reader.lines() {
-            let l = line.unwrap();
-            let fields: Vec<&str> = l.split(' ').collect();
-            if fields.len() != 4 {
-                println!("Line is not 4 elements!");
+        velocity: Option<u8>,
+        device: Option<&str>,
+    ) -> Option<Arc<NoteMapping>> {
+        if channel as usize >= CHANNELS {
+            return None;
+        }
+
+        if let Some(device) = device {
+            let mut device_tables = self.device_tables.borrow_mut();
+            if !device_tables.contains_key(device) {
+                let tables = self
+                    .devices
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (name, _))| device.contains(name.as_str()))
+                    .map(|(idx, _)| idx)
+                    .collect();
+                device_tables.insert(device.to_owned(), tables);
+            }
+            for &idx in &device_tables[device] {
+                if let Some(mapping) = self.devices[idx].1.find(note, channel, velocity) {
+                    return Some(mapping.clone());
+                }
+            }
+        }
+
+        self.generic.find(note, channel, velocity).cloned()
+    }
+
+    /// Load mappings from a file, replacing the current ones.  Files ending
+    /// in ".toml" are read as a structured profile, and anything else in the
+    /// line-based format.
+    ///
+    /// Nothing changes unless the whole file loads, and every problem in it
+    /// is reported at once.
+    pub fn import(&mut self, filename: &str) -> std::result::Result<(), ImportError> {
+        let (staged, diagnostics) = NoteMappings::read(filename, self.duplicates)?;
+        if !diagnostics.is_empty() {
+            return Err(ImportError::Invalid(diagnostics));
+        }
+
+        for conflict in staged.conflicts() {
+            println!("Warning: {}", conflict);
+        }
+        *self = staged;
+        Ok(())
+    }
+
+    /// Save these mappings to a file as a structured profile, which can
+    /// describe everything that a mapping does
+    pub fn export(&self, filename: &str) -> Result<()> {
+        fs::write(filename, profile::export(self))
+    }
+
+    /// Read a mappings file into a new set of mappings, using `duplicates`
+    /// when a note is mapped twice.  Anything with a problem is left out, and
+    /// the problems are returned alongside what could be loaded.
+    pub fn read(
+        filename: &str,
+        duplicates: DuplicatePolicy,
+    ) -> Result<(NoteMappings, Vec<Diagnostic>)> {
+        let mut mappings = NoteMappings::new();
+        mappings.duplicates = duplicates;
+        let diagnostics = if filename.ends_with(".toml") {
+            profile::import(&mut mappings, filename, &fs::read_to_string(filename)?)
+        } else {
+            mappings.import_lines(filename, BufReader::new(File::open(filename)?))?
+        };
+        Ok((mappings, diagnostics))
+    }
+
+    /// Load mappings in the line-based format, one note per line.
+    /// Returns the problems found, if any.
+    fn import_lines<R: BufRead>(
+        &mut self,
+        filename: &str,
+        buf_reader: R,
+    ) -> Result<Vec<Diagnostic>> {
+        let mut diagnostics = vec![];
+
+        // Set by a "[device name]" line, and applied to every line after it
+        let mut device: Option<String> = None;
+
+        for (idx, line) in buf_reader.lines().enumerate() {
+            let l = line?;
+            let trimmed = strip_comment(&l).trim();
+            if trimmed.starts_with('[') && trimmed.ends_with(']') {
+                let name = trimmed[1..trimmed.len() - 1].trim();
+                device = if name.is_empty() {
+                    None
+                } else {
+                    Some(name.to_owned())
+                };
+                continue;
+            }
+
+            let fields = split_fields(&l);
+            if fields.is_empty() {
                 continue;
             }
-            let note_txt = fields[0];
-            let channel_txt = fields[1];
-            let keydown_txt = fields[2];
-            let keyup_txt = fields[3];
+            for (column, message) in self.import_line(&fields, &device) {
+                diagnostics.push(Diagnostic {
+                    file: filename.to_owned(),
+                    line: idx + 1,
+                    column,
+                    message,
+                });
+            }
+        }
+        Ok(diagnostics)
+    }
+
+    /// Apply one line of a line-based mappings file.  Returns the column and
+    /// description of each problem with it.
+    fn import_line(
+        &mut self,
+        fields: &[(usize, &str)],
+        device: &Option<String>,
+    ) -> Vec<(usize, String)> {
+        let mut problems = vec![];
+        let (first_column, first) = fields[0];
+        match first {
+            // "modifiers KEY..." replaces the default set of state modifiers
+            "modifiers" => {
+                let mut modifiers = vec![];
+                for &(column, field) in &fields[1..] {
+                    match field.parse() {
+                        Ok(key) => modifiers.push(key),
+                        Err(e) => problems.push((column, e)),
+                    }
+                }
+                if problems.is_empty() {
+                    self.set_modifiers(modifiers);
+                }
+            }
+
+            // "timing NAME=MS..." sets the settle times for the whole profile
+            "timing" => {
+                for &(column, setting) in &fields[1..] {
+                    if let Err(e) = self.timing.parse_setting(setting) {
+                        problems.push((column, e));
+                    }
+                }
+            }
 
-            let note = MidiNote::new_from_text(&note_txt).unwrap();
-            let channel = channel_txt.parse::<u8>().unwrap();
-            let keydown = keydown_txt.chars().next().unwrap();
-            let keyup = keyup_txt.chars().next().unwrap();
+            // "octave UP DOWN [options]" sets the keys that change the game's octave
+            "octave" => {
+                let args: Vec<&str> = fields[1..].iter().map(|&(_, field)| field).collect();
+                match parse_octave_keys(&args) {
+                    Ok(octave_keys) => self.set_octave_keys(Some(octave_keys)),
+                    Err(e) => problems.push((first_column, e)),
+                }
+            }
+
+            // "panic TRIGGER CHANNEL" makes a note or controller release everything
+            "panic" => {
+                if fields.len() < 3 {
+                    let column = end_column(fields);
+                    problems.push((
+                        column,
+                        "expected a note or controller and a channel".to_owned(),
+                    ));
+                    return problems;
+                }
+                let trigger = fields[1]
+                    .1
+                    .parse()
+                    .map_err(|e| problems.push((fields[1].0, e)));
+                let channel =
+                    parse_channel(fields[2].1).map_err(|e| problems.push((fields[2].0, e)));
+                if let (Ok(trigger), Ok(channel)) = (trigger, channel) {
+                    self.add_panic(PanicTrigger {
+                        trigger,
+                        channel,
+                        instrument_name: device.clone(),
+                    });
+                }
+            }
 
-            let mut mapping = NoteMapping::new(note, channel, None);
-            mapping.on = NoteMapping::down_event(keydown, None, None);
-            mapping.off = NoteMapping::up_event(keyup, None, None);
+            _ => {
+                if fields.len() < 4 {
+                    let column = end_column(fields);
+                    problems.push((
+                        column,
+                        "expected a note, channel, keydown and keyup".to_owned(),
+                    ));
+                    return problems;
+                }
+                let note = fields[0].1.parse::<MidiNote>().map_err(|_| {
+                    problems.push((fields[0].0, format!("invalid note: {}", fields[0].1)))
+                });
+                let channel =
+                    parse_channel(fields[1].1).map_err(|e| problems.push((fields[1].0, e)));
+                let keydown = fields[2]
+                    .1
+                    .parse::<KbdKey>()
+                    .map_err(|e| problems.push((fields[2].0, e)));
+                let keyup = fields[3]
+                    .1
+                    .parse::<KbdKey>()
+                    .map_err(|e| problems.push((fields[3].0, e)));
+                // Any further fields are options of the form "name=value".
+                // These are checked even if the note is wrong, so that every
+                // problem is reported at once.
+                let mut velocity = 0..=127;
+                let mut release = ReleasePolicy::Finish;
+                let mut hold = HoldLimit::Default;
+                let mut timing = Timing::new();
+                let mut modifiers = vec![];
+                let mut octave = None;
+                for &(column, option) in &fields[4..] {
+                    let result = match option.split_once('=') {
+                        Some(("vel", range)) => parse_velocity_range(range)
+                            .map(|range| velocity = range)
+                            .ok_or_else(|| format!("invalid velocity range: {}", range)),
+                        Some(("release", policy)) => policy.parse().map(|policy| release = policy),
+                        Some(("mod", keys)) => parse_modifiers(keys).map(|keys| modifiers = keys),
+                        Some(("hold", limit)) => limit.parse().map(|limit| hold = limit),
+                        Some((name, msecs)) if name.ends_with("_delay") => timing.parse_setting(
+                            &format!("{}={}", name.trim_end_matches("_delay"), msecs),
+                        ),
+                        Some(("octave", value)) => value
+                            .parse::<i8>()
+                            .map(|value| octave = Some(value))
+                            .map_err(|_| format!("invalid octave: {}", value)),
+                        _ => Err(format!("unknown option: {}", option)),
+                    };
+                    if let Err(e) = result {
+                        problems.push((column, e));
+                    }
+                }
 
-            println!("Got line: {}  Mapping: {:?}", l, mapping);
-            self.add(mapping);
+                let (note, channel, keydown, keyup) = match (note, channel, keydown, keyup) {
+                    (Ok(note), Ok(channel), Ok(keydown), Ok(keyup)) if problems.is_empty() => {
+                        (note, channel, keydown, keyup)
+                    }
+                    _ => return problems,
+                };
+
+                let mut mapping = NoteMapping::new(note, channel, device.clone());
+                mapping.velocity = velocity;
+                mapping.release = release;
+                mapping.hold = hold;
+                mapping.timing = timing;
+                mapping.on.push(Event::NoteMod(modifiers));
+                // The octave goes after the modifiers, just before the key
+                if let Some(octave) = octave {
+                    mapping.on.push(Event::Octave(octave));
+                }
+                mapping.on.push(Event::KeyDown(keydown));
+                mapping.off.push(Event::KeyUp(keyup));
+
+                if let Err(e) = self.add(mapping) {
+                    problems.push((first_column, e.to_string()));
+                }
+            }
+        }
+        problems
+    }
+
+    /// Choose what `add` does when a mapping overlaps an existing one.
+    pub fn set_duplicate_policy(&mut self, policy: DuplicatePolicy) {
+        self.duplicates = policy;
+    }
+
+    /// Add a mapping.  If it overlaps an existing mapping for the same note,
+    /// channel and device (with overlapping velocity ranges), the duplicate
+    /// policy decides which one is kept.
+    pub fn add(&mut self, mapping: NoteMapping) -> std::result::Result<(), DuplicateMapping> {
+        if mapping.channel as usize >= CHANNELS {
+            println!("Ignoring mapping for invalid channel {}", mapping.channel);
+            return Ok(());
+        }
+
+        let table = match mapping.instrument_name {
+            None => &mut self.generic,
+            Some(ref name) => {
+                let idx = match self.devices.iter().position(|(n, _)| n == name) {
+                    Some(idx) => idx,
+                    None => {
+                        self.devices.push((name.clone(), MappingTable::new()));
+                        self.device_tables.borrow_mut().clear();
+                        self.devices.len() - 1
+                    }
+                };
+                &mut self.devices[idx].1
+            }
+        };
+
+        let slot = &mut table.slots[MappingTable::slot(mapping.channel, mapping.note)];
+        let overlaps = |existing: &Arc<NoteMapping>| {
+            existing.velocity.start() <= mapping.velocity.end()
+                && mapping.velocity.start() <= existing.velocity.end()
+        };
+        if let Some(existing) = slot.iter().find(|existing| overlaps(existing)) {
+            match self.duplicates {
+                DuplicatePolicy::FirstWins => {
+                    println!("Ignoring {}, already mapped by {}", mapping, existing);
+                    return Ok(());
+                }
+                DuplicatePolicy::LastWins => {
+                    println!("Replacing {} with {}", existing, mapping);
+                    slot.retain(|existing| !overlaps(existing));
+                }
+                DuplicatePolicy::Error => {
+                    return Err(DuplicateMapping {
+                        existing: existing.clone(),
+                        duplicate: Arc::new(mapping),
+                    })
+                }
+            }
         }
+        slot.push(Arc::new(mapping));
         Ok(())
     }
 
-    pub fn add(&mut self, mapping: NoteMapping) {
-        //Note: We need to remove old mappings here, too!
-        self.mappings.push(mapping);
+    /// Every mapping, device-specific ones after the generic ones.
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<NoteMapping>> {
+        self.generic
+            .slots
+            .iter()
+            .chain(
+                self.devices
+                    .iter()
+                    .flat_map(|(_, table)| table.slots.iter()),
+            )
+            .flatten()
+    }
+
+    /// Look for notes that could be held at the same time and that press the
+    /// same key.  Releasing either note releases the key for both, and if
+    /// they need different modifiers then at most one of them can sound right.
+    pub fn conflicts(&self) -> Vec<Conflict> {
+        let mut conflicts = vec![];
+        let mut tables = vec![(None, &self.generic)];
+        for (name, table) in &self.devices {
+            tables.push((Some(name), table));
+        }
+
+        // Collect the keys each mapping presses, along with the modifiers held
+        let mut pressed = vec![];
+        for (name, table) in tables {
+            for mapping in table.slots.iter().flatten() {
+                pressed.push((name, mapping, mapping.pressed_keys()));
+            }
+        }
+
+        for (idx, (first_device, first, first_keys)) in pressed.iter().enumerate() {
+            for (second_device, second, second_keys) in &pressed[idx + 1..] {
+                // Velocity layers of one note can't be held together, and
+                // mappings for two different devices never meet.
+                if (first.channel == second.channel && first.note == second.note)
+                    || (first_device.is_some()
+                        && second_device.is_some()
+                        && first_device != second_device)
+                {
+                    continue;
+                }
+                for (key, first_mod) in first_keys {
+                    if let Some((_, second_mod)) = second_keys.iter().find(|(k, _)| k == key) {
+                        conflicts.push(Conflict {
+                            key: key.clone(),
+                            first: (*first).clone(),
+                            second: (*second).clone(),
+                            modifiers_differ: !same_modifiers(first_mod, second_mod),
+                        });
+                    }
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Look for mappings that can never be played.  A panic button on the
+    /// same note wins over every mapping it covers, and a device's mappings
+    /// are searched in the order the devices first appeared, so a mapping for
+    /// "Keystation 49" is hidden wherever one for "Keystation" covers it.
+    pub fn unreachable(&self) -> Vec<Unreachable> {
+        let mut unreachable = vec![];
+        let tables = std::iter::once((None, &self.generic))
+            .chain(self.devices.iter().map(|(name, table)| (Some(name), table)));
+
+        for (idx, (name, table)) in tables.enumerate() {
+            // Device tables that are always searched before this one
+            let earlier: Vec<&(String, MappingTable)> = match name {
+                Some(name) => self.devices[..idx - 1]
+                    .iter()
+                    .filter(|(earlier, _)| name.contains(earlier.as_str()))
+                    .collect(),
+                None => vec![],
+            };
+
+            for mapping in table.slots.iter().flatten() {
+                let panic = self.panics.iter().find(|panic| {
+                    panic.trigger == Trigger::Note(mapping.note)
+                        && panic.channel == mapping.channel
+                        && panic.instrument_name.as_ref().is_none_or(|panic_name| {
+                            name.is_some_and(|name| name.contains(panic_name.as_str()))
+                        })
+                });
+                if let Some(panic) = panic {
+                    unreachable.push(Unreachable {
+                        mapping: mapping.clone(),
+                        taken_by: format!(
+                            "the panic button {} on channel {}",
+                            panic.trigger, panic.channel
+                        ),
+                    });
+                    continue;
+                }
+
+                // Each velocity goes to the first earlier table that maps it
+                let mut taken_by = vec![];
+                let covered = mapping.velocity.clone().all(|velocity| {
+                    let found = earlier.iter().find(|(_, earlier)| {
+                        earlier
+                            .find(mapping.note, mapping.channel, Some(velocity))
+                            .is_some()
+                    });
+                    if let Some((earlier_name, _)) = found {
+                        if !taken_by.contains(earlier_name) {
+                            taken_by.push(earlier_name.clone());
+                        }
+                    }
+                    found.is_some()
+                });
+                if covered {
+                    let names: Vec<String> = taken_by
+                        .iter()
+                        .map(|name| format!("\"{}\"", name))
+                        .collect();
+                    unreachable.push(Unreachable {
+                        mapping: mapping.clone(),
+                        taken_by: format!("the mapping for {}", names.join(" and ")),
+                    });
+                }
+            }
+        }
+        unreachable
+    }
+}
+
+/// A mapping that can never be played
+#[derive(Debug)]
+pub struct Unreachable {
+    pub mapping: Arc<NoteMapping>,
+
+    /// What is played instead, e.g. "the panic button C8 on channel 0"
+    pub taken_by: String,
+}
+
+impl fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} can never be played, since {} always comes first",
+            self.mapping, self.taken_by
+        )
+    }
+}
+
+/// What to do when a mapping is added for a note that is already mapped
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum DuplicatePolicy {
+    /// Keep the existing mapping, and ignore the new one
+    FirstWins,
+    /// Replace the existing mapping with the new one
+    LastWins,
+    /// Refuse to add the new mapping
+    Error,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<DuplicatePolicy, String> {
+        match s {
+            "first" => Ok(DuplicatePolicy::FirstWins),
+            "last" => Ok(DuplicatePolicy::LastWins),
+            "error" => Ok(DuplicatePolicy::Error),
+            _ => Err(format!("unknown duplicate policy: {}", s)),
+        }
+    }
+}
+
+/// A mapping that was refused because it duplicates an existing one
+#[derive(Debug)]
+pub struct DuplicateMapping {
+    pub existing: Arc<NoteMapping>,
+    pub duplicate: Arc<NoteMapping>,
+}
+
+impl fmt::Display for DuplicateMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is already mapped by {}",
+            self.duplicate, self.existing
+        )
+    }
+}
+
+impl Error for DuplicateMapping {}
+
+/// A problem at one place in a mappings file
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+/// Why a mappings file couldn't be loaded
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    /// The file was read, but has mistakes in it
+    Invalid(Vec<Diagnostic>),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Io(e) => write!(f, "unable to read mappings: {}", e),
+            ImportError::Invalid(diagnostics) => {
+                for (idx, diagnostic) in diagnostics.iter().enumerate() {
+                    if idx > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ImportError {}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> ImportError {
+        ImportError::Io(e)
+    }
+}
+
+/// Two mappings that press the same key
+#[derive(Debug)]
+pub struct Conflict {
+    pub key: KbdKey,
+    pub first: Arc<NoteMapping>,
+    pub second: Arc<NoteMapping>,
+
+    /// Whether the two mappings need different modifiers held for the key
+    pub modifiers_differ: bool,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.modifiers_differ {
+            write!(
+                f,
+                "{} and {} both press {} with different modifiers",
+                self.first, self.second, self.key
+            )
+        } else {
+            write!(
+                f,
+                "{} and {} both press {}",
+                self.first, self.second, self.key
+            )
+        }
     }
 }
@@ -0,0 +1,412 @@
+//! The structured profile format, which is TOML.  Unlike the line-based
+//! format it can express any sequence of events, e.g.
+//!
+//! ```toml
+//! modifiers = ["shift", "ctrl"]
+//! timing = { key = 40, sys = 400 }
+//!
+//! [[mapping]]
+//! note = "C4"
+//! key = "q"
+//! mod = "shift"
+//!
+//! [[mapping]]
+//! note = 40
+//! channel = 9
+//! device = "Nitro"
+//! velocity = "64-127"
+//! on = ["mod none", "down escape", "settle key", "up escape", "settle sys"]
+//! ```
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use super::{
+    parse_modifiers, parse_velocity_range, Diagnostic, Event, HoldLimit, KbdKey, NoteMapping,
+    NoteMappings, OctaveKeys, PanicTrigger, ReleasePolicy, Timing, Trigger, CHANNELS, SETTLES,
+};
+use crate::midi::MidiNote;
+
+/// A value that may be written either as a number or as a string, such as
+/// a note ("C4" or 60) or a hold time (500 or "none")
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Number(i64),
+    Text(String),
+}
+
+impl Value {
+    fn text(&self) -> String {
+        match self {
+            Value::Number(n) => n.to_string(),
+            Value::Text(s) => s.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    modifiers: Option<Vec<String>>,
+    #[serde(default)]
+    timing: BTreeMap<String, u64>,
+    octave: Option<OctaveSection>,
+    #[serde(default)]
+    panic: Vec<PanicSection>,
+    #[serde(default)]
+    mapping: Vec<MappingSection>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OctaveSection {
+    up: String,
+    down: String,
+    min: Option<i8>,
+    max: Option<i8>,
+    #[serde(default)]
+    wrap: bool,
+    settle: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PanicSection {
+    trigger: Value,
+    #[serde(default)]
+    channel: u8,
+    device: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappingSection {
+    note: Value,
+    #[serde(default)]
+    channel: u8,
+    device: Option<String>,
+    velocity: Option<Value>,
+    release: Option<String>,
+    hold: Option<Value>,
+    #[serde(default)]
+    timing: BTreeMap<String, u64>,
+
+    /// A single key to press and release with the note, instead of `on`
+    /// and `off`
+    key: Option<String>,
+    #[serde(rename = "mod")]
+    modifiers: Option<String>,
+    octave: Option<i8>,
+
+    on: Option<Vec<String>>,
+    off: Option<Vec<String>>,
+}
+
+fn parse_timing(settings: &BTreeMap<String, u64>) -> Result<Timing, String> {
+    let mut timing = Timing::new();
+    for (name, msecs) in settings {
+        timing.set(name.parse()?, *msecs);
+    }
+    Ok(timing)
+}
+
+fn parse_channel(channel: u8) -> Result<u8, String> {
+    if channel as usize >= CHANNELS {
+        return Err(format!("invalid channel: {}", channel));
+    }
+    Ok(channel)
+}
+
+fn parse_events(events: &[String]) -> Result<Vec<Event>, String> {
+    events.iter().map(|event| event.parse()).collect()
+}
+
+impl OctaveSection {
+    fn to_octave_keys(&self) -> Result<OctaveKeys, String> {
+        let mut octave_keys = OctaveKeys::new(self.up.parse()?, self.down.parse()?);
+        octave_keys.min = self.min.unwrap_or(octave_keys.min);
+        octave_keys.max = self.max.unwrap_or(octave_keys.max);
+        octave_keys.wrap = self.wrap;
+        octave_keys.settle_ms = self.settle;
+        if octave_keys.min > 0 || octave_keys.max < 0 {
+            return Err("the octave range must include the starting octave 0".to_owned());
+        }
+        Ok(octave_keys)
+    }
+}
+
+impl MappingSection {
+    fn to_mapping(&self) -> Result<NoteMapping, String> {
+        let note = self
+            .note
+            .text()
+            .parse::<MidiNote>()
+            .map_err(|_| format!("invalid note: {}", self.note.text()))?;
+        let mut mapping = NoteMapping::new(note, parse_channel(self.channel)?, self.device.clone());
+
+        if let Some(ref velocity) = self.velocity {
+            mapping.velocity = parse_velocity_range(&velocity.text())
+                .ok_or_else(|| format!("invalid velocity range: {}", velocity.text()))?;
+        }
+        if let Some(ref release) = self.release {
+            mapping.release = release.parse()?;
+        }
+        if let Some(ref hold) = self.hold {
+            mapping.hold = hold.text().parse()?;
+        }
+        mapping.timing = parse_timing(&self.timing)?;
+
+        match self.key {
+            Some(ref key) => {
+                if self.on.is_some() || self.off.is_some() {
+                    return Err("a mapping can't have both a key and on/off events".to_owned());
+                }
+                let key: KbdKey = key.parse()?;
+                let modifiers = match self.modifiers {
+                    Some(ref modifiers) => parse_modifiers(modifiers)?,
+                    None => vec![],
+                };
+                mapping.on.push(Event::NoteMod(modifiers));
+                if let Some(octave) = self.octave {
+                    mapping.on.push(Event::Octave(octave));
+                }
+                mapping.on.push(Event::KeyDown(key.clone()));
+                mapping.off.push(Event::KeyUp(key));
+            }
+            None => {
+                if self.modifiers.is_some() || self.octave.is_some() {
+                    return Err("mod and octave only apply to a mapping with a key".to_owned());
+                }
+                mapping.on = parse_events(self.on.as_deref().unwrap_or_default())?;
+                mapping.off = parse_events(self.off.as_deref().unwrap_or_default())?;
+            }
+        }
+        Ok(mapping)
+    }
+}
+
+/// The line that each table with the given header starts on, counting from 1.
+/// The TOML parser doesn't say where values came from, so this is used to
+/// point problems at the right table.  Headers may have a comment after
+/// them and spaces inside the brackets, e.g. `[[ mapping ]] # pads`.
+fn header_lines(text: &str, header: &str) -> Vec<usize> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.split('#').next().unwrap_or_default();
+            line.chars()
+                .filter(|c| !c.is_whitespace())
+                .eq(header.chars())
+        })
+        .map(|(idx, _)| idx + 1)
+        .collect()
+}
+
+/// Load a TOML profile into `mappings`.  Returns the problems found, if any.
+pub(super) fn import(mappings: &mut NoteMappings, filename: &str, text: &str) -> Vec<Diagnostic> {
+    let diagnostic = |line: usize, column: usize, message: String| Diagnostic {
+        file: filename.to_owned(),
+        line,
+        column,
+        message,
+    };
+
+    let file: ProfileFile = match toml::from_str(text) {
+        Ok(file) => file,
+        Err(e) => {
+            let (line, column) = e.line_col().map_or((1, 1), |(l, c)| (l + 1, c + 1));
+            return vec![diagnostic(line, column, e.to_string())];
+        }
+    };
+
+    // Problems with the top-level settings can't be pinned down any further
+    let mut diagnostics = vec![];
+    if let Some(ref modifiers) = file.modifiers {
+        match modifiers.iter().map(|key| key.parse()).collect() {
+            Ok(modifiers) => mappings.set_modifiers(modifiers),
+            Err(e) => diagnostics.push(diagnostic(1, 1, format!("modifiers: {}", e))),
+        }
+    }
+    match parse_timing(&file.timing) {
+        Ok(timing) => mappings.set_timing(timing),
+        Err(e) => diagnostics.push(diagnostic(1, 1, format!("timing: {}", e))),
+    }
+    if let Some(ref octave) = file.octave {
+        match octave.to_octave_keys() {
+            Ok(octave_keys) => mappings.set_octave_keys(Some(octave_keys)),
+            Err(e) => diagnostics.push(diagnostic(1, 1, format!("octave: {}", e))),
+        }
+    }
+
+    let panic_lines = header_lines(text, "[[panic]]");
+    for (idx, panic) in file.panic.iter().enumerate() {
+        let line = panic_lines.get(idx).copied().unwrap_or(1);
+        let trigger = panic
+            .trigger
+            .text()
+            .parse()
+            .and_then(|trigger| Ok((trigger, parse_channel(panic.channel)?)));
+        match trigger {
+            Ok((trigger, channel)) => mappings.add_panic(PanicTrigger {
+                trigger,
+                channel,
+                instrument_name: panic.device.clone(),
+            }),
+            Err(e) => diagnostics.push(diagnostic(line, 1, e)),
+        }
+    }
+
+    let mapping_lines = header_lines(text, "[[mapping]]");
+    for (idx, section) in file.mapping.iter().enumerate() {
+        let line = mapping_lines.get(idx).copied().unwrap_or(1);
+        let result = section
+            .to_mapping()
+            .and_then(|mapping| mappings.add(mapping).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            diagnostics.push(diagnostic(line, 1, e));
+        }
+    }
+    diagnostics
+}
+
+/// A string written as a TOML string, with quotes and escapes
+fn quote(text: &str) -> String {
+    toml::Value::String(text.to_owned()).to_string()
+}
+
+/// The settle times that are set, as an inline table
+fn timing_table(timing: &Timing) -> Option<String> {
+    let settings: Vec<String> = SETTLES
+        .iter()
+        .filter_map(|&(name, settle, _)| timing.get(settle).map(|ms| format!("{} = {}", name, ms)))
+        .collect();
+    if settings.is_empty() {
+        return None;
+    }
+    Some(format!("{{ {} }}", settings.join(", ")))
+}
+
+/// A list of events, one per line if there are more than a few
+fn event_list(events: &[Event]) -> String {
+    let events: Vec<String> = events
+        .iter()
+        .map(|event| quote(&event.to_string()))
+        .collect();
+    if events.len() <= 4 {
+        return format!("[{}]", events.join(", "));
+    }
+    format!("[\n    {},\n]", events.join(",\n    "))
+}
+
+/// The key, modifiers and octave of a mapping that just presses one key
+/// while the note is held, so that it can be written with `key`
+fn single_key(mapping: &NoteMapping) -> Option<(&KbdKey, &[KbdKey], Option<i8>)> {
+    let (key, modifiers, octave) = match mapping.on.as_slice() {
+        [Event::NoteMod(modifiers), Event::KeyDown(key)] => (key, modifiers, None),
+        [Event::NoteMod(modifiers), Event::Octave(octave), Event::KeyDown(key)] => {
+            (key, modifiers, Some(*octave))
+        }
+        _ => return None,
+    };
+    match mapping.off.as_slice() {
+        [Event::KeyUp(up)] if up == key => Some((key, modifiers.as_slice(), octave)),
+        _ => None,
+    }
+}
+
+/// A setting for a note.  Notes are written as numbers, which mean the same
+/// whatever `--middle-c` is set to, with the name alongside for reading.
+fn note_line(name: &str, note: MidiNote) -> String {
+    format!("{} = {} # {}", name, note.index(), note)
+}
+
+/// Write `mappings` as a TOML profile that loads back into the same mappings
+pub(super) fn export(mappings: &NoteMappings) -> String {
+    let mut lines = vec![];
+    let modifiers: Vec<String> = mappings
+        .modifiers
+        .iter()
+        .map(|key| quote(&key.to_string()))
+        .collect();
+    lines.push(format!("modifiers = [{}]", modifiers.join(", ")));
+    if let Some(timing) = timing_table(&mappings.timing) {
+        lines.push(format!("timing = {}", timing));
+    }
+    if let Some(ref octave) = mappings.octave_keys {
+        let mut settings = vec![
+            format!("up = {}", quote(&octave.up.to_string())),
+            format!("down = {}", quote(&octave.down.to_string())),
+            format!("min = {}", octave.min),
+            format!("max = {}", octave.max),
+            format!("wrap = {}", octave.wrap),
+        ];
+        if let Some(settle) = octave.settle_ms {
+            settings.push(format!("settle = {}", settle));
+        }
+        lines.push(format!("octave = {{ {} }}", settings.join(", ")));
+    }
+
+    for panic in &mappings.panics {
+        lines.push(String::new());
+        lines.push("[[panic]]".to_owned());
+        match panic.trigger {
+            Trigger::Note(note) => lines.push(note_line("trigger", note)),
+            Trigger::Control(_) => {
+                lines.push(format!("trigger = {}", quote(&panic.trigger.to_string())))
+            }
+        }
+        lines.push(format!("channel = {}", panic.channel));
+        if let Some(ref name) = panic.instrument_name {
+            lines.push(format!("device = {}", quote(name)));
+        }
+    }
+
+    for mapping in mappings.iter() {
+        lines.push(String::new());
+        lines.push("[[mapping]]".to_owned());
+        lines.push(note_line("note", mapping.note));
+        lines.push(format!("channel = {}", mapping.channel));
+        if let Some(ref name) = mapping.instrument_name {
+            lines.push(format!("device = {}", quote(name)));
+        }
+        if mapping.velocity != (0..=127) {
+            let (lo, hi) = (mapping.velocity.start(), mapping.velocity.end());
+            lines.push(format!("velocity = \"{}-{}\"", lo, hi));
+        }
+        if mapping.release != ReleasePolicy::Finish {
+            lines.push(format!("release = {}", quote(&mapping.release.to_string())));
+        }
+        match mapping.hold {
+            HoldLimit::Default => (),
+            HoldLimit::Unlimited => lines.push("hold = \"none\"".to_owned()),
+            HoldLimit::Millis(msecs) => lines.push(format!("hold = {}", msecs)),
+        }
+        if let Some(timing) = timing_table(&mapping.timing) {
+            lines.push(format!("timing = {}", timing));
+        }
+
+        match single_key(mapping) {
+            Some((key, modifiers, octave)) => {
+                lines.push(format!("key = {}", quote(&key.to_string())));
+                if !modifiers.is_empty() {
+                    let names: Vec<String> = modifiers.iter().map(KbdKey::to_string).collect();
+                    lines.push(format!("mod = {}", quote(&names.join("+"))));
+                }
+                if let Some(octave) = octave {
+                    lines.push(format!("octave = {}", octave));
+                }
+            }
+            None => {
+                lines.push(format!("on = {}", event_list(&mapping.on)));
+                if !mapping.off.is_empty() {
+                    lines.push(format!("off = {}", event_list(&mapping.off)));
+                }
+            }
+        }
+    }
+
+    lines.push(String::new());
+    lines.join("\n")
+}
@@ -0,0 +1,208 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use enigo::{Enigo, KeyboardControllable};
+
+use crate::notemappings::{KbdKey, NoteMapping};
+use crate::scheduler::Phase;
+
+#[cfg(target_os = "linux")]
+mod uinput;
+#[cfg(target_os = "linux")]
+pub use uinput::UinputBackend;
+
+thread_local!(static ENIGO: RefCell<Enigo> = RefCell::new(Default::default()));
+
+/// The names of the backends that can be picked with `new_backend`
+#[cfg(target_os = "linux")]
+pub const BACKEND_NAMES: &[&str] = &["enigo", "uinput", "dry-run"];
+#[cfg(not(target_os = "linux"))]
+pub const BACKEND_NAMES: &[&str] = &["enigo", "dry-run"];
+
+/// Create an output backend by name
+pub fn new_backend(name: &str) -> io::Result<Box<dyn OutputBackend>> {
+    match name {
+        "enigo" => Ok(Box::new(EnigoBackend::new())),
+        "dry-run" => Ok(Box::new(DryRunBackend::new())),
+        #[cfg(target_os = "linux")]
+        "uinput" => Ok(Box::new(UinputBackend::new()?)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown output backend: {}", name),
+        )),
+    }
+}
+
+/// Whether the named backend has a way of pressing `key`.  uinput only
+/// knows the keys on a US keyboard, and nothing can type a control character
+/// given as a layout key.
+pub fn can_press(name: &str, key: &KbdKey) -> bool {
+    match name {
+        "dry-run" => true,
+        #[cfg(target_os = "linux")]
+        "uinput" => uinput::evdev_code(key).is_some_and(|(code, _)| uinput::is_advertised(code)),
+        _ => match *key {
+            KbdKey::Layout(c) => !c.is_control(),
+            _ => true,
+        },
+    }
+}
+
+/// Somewhere to send key presses.  `KeyGen` keeps track of which keys are
+/// held, so backends only need to pass the events along.
+///
+/// The scheduler also reports what it is doing between key events, which
+/// backends that only type keys can ignore.
+pub trait OutputBackend: Send {
+    fn key_down(&mut self, key: &KbdKey);
+    fn key_up(&mut self, key: &KbdKey);
+
+    /// A mapping's on or off sequence is starting
+    fn sequence_start(&mut self, _mapping: &NoteMapping, _phase: Phase) {}
+
+    /// The held modifiers were changed to `_modifiers` for the next note
+    fn modifier_change(&mut self, _modifiers: &[KbdKey]) {}
+
+    /// The current sequence is pausing for `_msecs` before its next event
+    fn delay(&mut self, _msecs: u64) {}
+}
+
+/// Simulate keys using the system's input APIs, through enigo
+#[derive(Default)]
+pub struct EnigoBackend;
+
+impl EnigoBackend {
+    pub fn new() -> EnigoBackend {
+        EnigoBackend
+    }
+}
+
+impl OutputBackend for EnigoBackend {
+    fn key_down(&mut self, key: &KbdKey) {
+        ENIGO.with(|enigo| enigo.borrow_mut().key_down(KbdKey::to_enigo_key(key)));
+    }
+
+    fn key_up(&mut self, key: &KbdKey) {
+        ENIGO.with(|enigo| enigo.borrow_mut().key_up(KbdKey::to_enigo_key(key)));
+    }
+}
+
+/// One entry in a `RecordingBackend` log
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keystroke {
+    /// Time since the backend was created
+    pub time: Duration,
+    pub key: KbdKey,
+    /// `true` for a key press, `false` for a release
+    pub down: bool,
+}
+
+/// Store every key event in a log instead of typing it, so that mappings can
+/// be exercised without a display server.  Clones share the same log.
+#[derive(Clone)]
+pub struct RecordingBackend {
+    start: Instant,
+    log: Arc<Mutex<Vec<Keystroke>>>,
+}
+
+impl Default for RecordingBackend {
+    fn default() -> RecordingBackend {
+        RecordingBackend {
+            start: Instant::now(),
+            log: Default::default(),
+        }
+    }
+}
+
+impl RecordingBackend {
+    pub fn new() -> RecordingBackend {
+        RecordingBackend::default()
+    }
+
+    /// A copy of everything recorded so far
+    pub fn log(&self) -> Vec<Keystroke> {
+        self.log.lock().unwrap().clone()
+    }
+
+    fn record(&mut self, key: &KbdKey, down: bool) {
+        self.log.lock().unwrap().push(Keystroke {
+            time: self.start.elapsed(),
+            key: key.clone(),
+            down,
+        });
+    }
+}
+
+impl OutputBackend for RecordingBackend {
+    fn key_down(&mut self, key: &KbdKey) {
+        self.record(key, true);
+    }
+
+    fn key_up(&mut self, key: &KbdKey) {
+        self.record(key, false);
+    }
+}
+
+/// Print every key event and delay instead of typing it, with the time
+/// since the backend was created and the note that caused it.
+pub struct DryRunBackend {
+    start: Instant,
+}
+
+impl Default for DryRunBackend {
+    fn default() -> DryRunBackend {
+        DryRunBackend {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl DryRunBackend {
+    pub fn new() -> DryRunBackend {
+        DryRunBackend::default()
+    }
+
+    fn print(&self, what: fmt::Arguments) {
+        let elapsed = self.start.elapsed();
+        println!(
+            "{:>5}.{:03}  {}",
+            elapsed.as_secs(),
+            elapsed.subsec_millis(),
+            what
+        );
+    }
+}
+
+impl OutputBackend for DryRunBackend {
+    fn key_down(&mut self, key: &KbdKey) {
+        self.print(format_args!("    key down {}", key));
+    }
+
+    fn key_up(&mut self, key: &KbdKey) {
+        self.print(format_args!("    key up   {}", key));
+    }
+
+    fn sequence_start(&mut self, mapping: &NoteMapping, phase: Phase) {
+        let phase = match phase {
+            Phase::On => "note on",
+            Phase::Off => "note off",
+        };
+        self.print(format_args!("{}: {}", mapping, phase));
+    }
+
+    fn modifier_change(&mut self, modifiers: &[KbdKey]) {
+        if modifiers.is_empty() {
+            self.print(format_args!("    no modifiers"));
+        } else {
+            let names: Vec<String> = modifiers.iter().map(KbdKey::to_string).collect();
+            self.print(format_args!("    modifiers {}", names.join("+")));
+        }
+    }
+
+    fn delay(&mut self, msecs: u64) {
+        self.print(format_args!("    delay {} ms", msecs));
+    }
+}
@@ -0,0 +1,244 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::slice;
+
+use super::OutputBackend;
+use crate::notemappings::KbdKey;
+
+// ioctl requests from linux/uinput.h
+const UI_DEV_CREATE: libc::c_ulong = 0x5501;
+const UI_DEV_DESTROY: libc::c_ulong = 0x5502;
+const UI_DEV_SETUP: libc::c_ulong = 0x405c_5503;
+const UI_SET_EVBIT: libc::c_ulong = 0x4004_5564;
+const UI_SET_KEYBIT: libc::c_ulong = 0x4004_5565;
+
+// Event types and codes from linux/input-event-codes.h
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const SYN_REPORT: u16 = 0;
+const BUS_VIRTUAL: u16 = 0x06;
+
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_LEFTALT: u16 = 56;
+const KEY_LEFTMETA: u16 = 125;
+
+/// The highest key code we advertise, which covers every key on a normal keyboard
+const KEY_MAX_ADVERTISED: u16 = 255;
+
+#[repr(C)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+#[repr(C)]
+struct UinputSetup {
+    id: InputId,
+    name: [u8; 80],
+    ff_effects_max: u32,
+}
+
+#[repr(C)]
+struct InputEvent {
+    time: libc::timeval,
+    kind: u16,
+    code: u16,
+    value: i32,
+}
+
+/// Type keys through a virtual keyboard created with /dev/uinput.  This works
+/// under Wayland and on a bare console, where X11 events do not.  The user
+/// needs write access to /dev/uinput.
+pub struct UinputBackend {
+    device: File,
+}
+
+impl UinputBackend {
+    pub fn new() -> io::Result<UinputBackend> {
+        let device = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/uinput")?;
+        let fd = device.as_raw_fd();
+
+        let mut setup = UinputSetup {
+            id: InputId {
+                bustype: BUS_VIRTUAL,
+                vendor: 0x1209,
+                product: 0x0001,
+                version: 1,
+            },
+            name: [0; 80],
+            ff_effects_max: 0,
+        };
+        let name = b"miditran virtual keyboard";
+        setup.name[..name.len()].copy_from_slice(name);
+
+        unsafe {
+            check(libc::ioctl(
+                fd,
+                UI_SET_EVBIT as _,
+                libc::c_int::from(EV_KEY),
+            ))?;
+            check(libc::ioctl(
+                fd,
+                UI_SET_EVBIT as _,
+                libc::c_int::from(EV_SYN),
+            ))?;
+            for code in 1..=KEY_MAX_ADVERTISED {
+                check(libc::ioctl(fd, UI_SET_KEYBIT as _, libc::c_int::from(code)))?;
+            }
+            check(libc::ioctl(
+                fd,
+                UI_DEV_SETUP as _,
+                &setup as *const UinputSetup,
+            ))?;
+            check(libc::ioctl(fd, UI_DEV_CREATE as _))?;
+        }
+
+        Ok(UinputBackend { device })
+    }
+
+    fn emit(&mut self, kind: u16, code: u16, value: i32) -> io::Result<()> {
+        let event = InputEvent {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            kind,
+            code,
+            value,
+        };
+        let bytes = unsafe {
+            slice::from_raw_parts(
+                &event as *const InputEvent as *const u8,
+                mem::size_of::<InputEvent>(),
+            )
+        };
+        self.device.write_all(bytes)
+    }
+
+    fn send_key(&mut self, key: &KbdKey, down: bool) {
+        let (code, shifted) = match evdev_code(key) {
+            Some(code) => code,
+            None => {
+                println!("Unable to type {:?} with uinput", key);
+                return;
+            }
+        };
+
+        // Shifted characters hold the right shift key, so that they don't
+        // interfere with the left one being used as a modifier.
+        let value = i32::from(down);
+        let result = if shifted && down {
+            self.emit(EV_KEY, KEY_RIGHTSHIFT, 1)
+                .and_then(|_| self.emit(EV_KEY, code, value))
+        } else if shifted {
+            self.emit(EV_KEY, code, value)
+                .and_then(|_| self.emit(EV_KEY, KEY_RIGHTSHIFT, 0))
+        } else {
+            self.emit(EV_KEY, code, value)
+        }
+        .and_then(|_| self.emit(EV_SYN, SYN_REPORT, 0));
+
+        if let Err(e) = result {
+            println!("Unable to send {:?} to uinput: {}", key, e);
+        }
+    }
+}
+
+impl OutputBackend for UinputBackend {
+    fn key_down(&mut self, key: &KbdKey) {
+        self.send_key(key, true);
+    }
+
+    fn key_up(&mut self, key: &KbdKey) {
+        self.send_key(key, false);
+    }
+}
+
+impl Drop for UinputBackend {
+    fn drop(&mut self) {
+        unsafe {
+            libc::ioctl(self.device.as_raw_fd(), UI_DEV_DESTROY as _);
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether the virtual keyboard can send a key code
+pub fn is_advertised(code: u16) -> bool {
+    (1..=KEY_MAX_ADVERTISED).contains(&code)
+}
+
+/// Translate a key into an evdev key code, along with whether shift needs to
+/// be held to get it.  Layout keys are translated for a US keyboard.
+pub fn evdev_code(key: &KbdKey) -> Option<(u16, bool)> {
+    let code = match *key {
+        KbdKey::Return => 28,
+        KbdKey::Tab => 15,
+        KbdKey::Space => 57,
+        KbdKey::Backspace => 14,
+        KbdKey::Escape => 1,
+        KbdKey::Meta => KEY_LEFTMETA,
+        KbdKey::Shift => KEY_LEFTSHIFT,
+        KbdKey::CapsLock => 58,
+        KbdKey::Alt | KbdKey::Option => KEY_LEFTALT,
+        KbdKey::Control => KEY_LEFTCTRL,
+        KbdKey::Home => 102,
+        KbdKey::PageUp => 104,
+        KbdKey::PageDown => 109,
+        KbdKey::LeftArrow => 105,
+        KbdKey::RightArrow => 106,
+        KbdKey::DownArrow => 108,
+        KbdKey::UpArrow => 103,
+        KbdKey::F1 => 59,
+        KbdKey::F2 => 60,
+        KbdKey::F3 => 61,
+        KbdKey::F4 => 62,
+        KbdKey::F5 => 63,
+        KbdKey::F6 => 64,
+        KbdKey::F7 => 65,
+        KbdKey::F8 => 66,
+        KbdKey::F9 => 67,
+        KbdKey::F10 => 68,
+        KbdKey::F11 => 87,
+        KbdKey::F12 => 88,
+        KbdKey::Raw(code) => code,
+        KbdKey::Layout(c) => return layout_code(c),
+    };
+    Some((code, false))
+}
+
+fn layout_code(c: char) -> Option<(u16, bool)> {
+    const UNSHIFTED: &str = "1234567890-=\tqwertyuiop[]\nasdfghjkl;'`\\zxcvbnm,./ ";
+    const SHIFTED: &str = "!@#$%^&*()_+\tQWERTYUIOP{}\nASDFGHJKL:\"~|ZXCVBNM<>? ";
+    // Key codes for each position in the strings above
+    const CODES: [u16; 50] = [
+        2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+        28, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52,
+        53, 57,
+    ];
+
+    if let Some(pos) = UNSHIFTED.chars().position(|x| x == c) {
+        return CODES.get(pos).map(|&code| (code, false));
+    }
+    if let Some(pos) = SHIFTED.chars().position(|x| x == c) {
+        return CODES.get(pos).map(|&code| (code, true));
+    }
+    None
+}
@@ -0,0 +1,92 @@
+use std::time::Instant;
+
+use crate::smf::{self, SmfError, SmfTrack};
+
+/// Everything captured from one MIDI device
+struct RecordedPort {
+    name: String,
+
+    /// Added to midir timestamps for this device to place them on the
+    /// recording's timeline, since each connection has its own time base.
+    /// `None` until the first message after the device (re)connects.
+    offset_us: Option<i64>,
+
+    events: Vec<(u64, Vec<u8>)>,
+}
+
+/// Captures incoming MIDI messages so they can be saved as a MIDI file.
+pub struct Recorder {
+    start: Instant,
+    ports: Vec<RecordedPort>,
+}
+
+impl Default for Recorder {
+    fn default() -> Recorder {
+        Recorder {
+            start: Instant::now(),
+            ports: vec![],
+        }
+    }
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder::default()
+    }
+
+    /// Note that a device has been connected.  midir timestamps start again
+    /// from zero on every connection, so if a device is unplugged and comes
+    /// back under the same name, its next message is lined up afresh.
+    pub fn connected(&mut self, port_name: &str) {
+        if let Some(port) = self.ports.iter_mut().find(|port| port.name == port_name) {
+            port.offset_us = None;
+        }
+    }
+
+    /// Store a message as delivered by midir.  The timestamp is only used
+    /// relative to other messages from the same connection; the first message
+    /// from each connection is lined up with the time it arrived.
+    pub fn record(&mut self, port_name: &str, timestamp_us: u64, raw_message: &[u8]) {
+        let idx = match self.ports.iter().position(|port| port.name == port_name) {
+            Some(idx) => idx,
+            None => {
+                self.ports.push(RecordedPort {
+                    name: port_name.to_owned(),
+                    offset_us: None,
+                    events: vec![],
+                });
+                self.ports.len() - 1
+            }
+        };
+        let elapsed_us = self.start.elapsed().as_micros() as i64;
+        let port = &mut self.ports[idx];
+        let offset_us = *port
+            .offset_us
+            .get_or_insert(elapsed_us - timestamp_us as i64);
+        let time_us = (timestamp_us as i64 + offset_us).max(0) as u64;
+        port.events.push((time_us, raw_message.to_vec()));
+    }
+
+    /// The number of messages captured so far
+    pub fn len(&self) -> usize {
+        self.ports.iter().map(|port| port.events.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write everything captured so far to a type 0 or type 1 MIDI file.
+    /// Type 1 files get one track per device.
+    pub fn save(&self, path: &str, format: u16) -> Result<(), SmfError> {
+        let tracks: Vec<SmfTrack> = self
+            .ports
+            .iter()
+            .map(|port| SmfTrack {
+                name: port.name.clone(),
+                events: port.events.clone(),
+            })
+            .collect();
+        smf::write_file(path, format, &tracks)
+    }
+}
@@ -0,0 +1,670 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::appstate::{KeyGen, OctaveStep};
+use crate::midi::MidiNote;
+use crate::notemappings::{Event, KbdKey, NoteMapping, ReleasePolicy, Settle};
+
+/// Which of a mapping's sequences a job runs
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Phase {
+    On,
+    Off,
+}
+
+/// Identifies the note that a sequence was started for, so that a release
+/// or retrigger can find it.  The device name is hashed, so that building a
+/// `Voice` doesn't need an allocation.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub struct Voice {
+    device: u64,
+    channel: u8,
+    note: MidiNote,
+}
+
+impl Voice {
+    pub fn new(device: Option<&str>, channel: u8, note: MidiNote) -> Voice {
+        let mut hasher = DefaultHasher::new();
+        device.hash(&mut hasher);
+        Voice {
+            device: hasher.finish(),
+            channel,
+            note,
+        }
+    }
+}
+
+/// A sequence that is waiting to run, or that is partway through running
+struct Job {
+    due: Instant,
+
+    /// Breaks ties so that jobs due at the same time run in the order they were queued
+    order: u64,
+
+    mapping: Arc<NoteMapping>,
+    phase: Phase,
+    voice: Voice,
+
+    /// Index of the next event to run
+    pos: usize,
+
+    /// Run the rest of the sequence immediately, skipping any delays
+    truncate: bool,
+
+    /// Set from a NoteMod or Octave event until the next KeyDown, while the
+    /// key still depends on the modifiers and octave staying as they are
+    holds_modifiers: bool,
+
+    /// Keys that the sequence pressed with KeyDown and hasn't released yet
+    pressed: Vec<KbdKey>,
+}
+
+impl Job {
+    fn events(&self) -> &[Event] {
+        match self.phase {
+            Phase::On => &self.mapping.on,
+            Phase::Off => &self.mapping.off,
+        }
+    }
+
+    /// Whether the next event changes the state modifiers or the octave
+    fn wants_modifiers(&self, state_modifiers: &[KbdKey]) -> bool {
+        match self.events().get(self.pos) {
+            Some(Event::NoteMod(_)) | Some(Event::Octave(_)) => true,
+            Some(Event::KeyDown(key)) | Some(Event::KeyUp(key)) => state_modifiers.contains(key),
+            _ => false,
+        }
+    }
+
+    /// Whether the sequence depends on the modifiers and octave staying as
+    /// they are until it runs again
+    fn owns_modifiers(&self, state_modifiers: &[KbdKey]) -> bool {
+        self.holds_modifiers || self.pressed.iter().any(|key| state_modifiers.contains(key))
+    }
+}
+
+// BinaryHeap is a max-heap, so order jobs backwards to get the earliest first.
+impl Ord for Job {
+    fn cmp(&self, other: &Job) -> Ordering {
+        other
+            .due
+            .cmp(&self.due)
+            .then_with(|| other.order.cmp(&self.order))
+    }
+}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Job) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Job) -> bool {
+        self.order == other.order
+    }
+}
+
+impl Eq for Job {}
+
+/// The sequence currently running for a note, and any waiting behind it
+struct VoiceState {
+    /// The `order` of the job that is running
+    current: u64,
+
+    /// Jobs for this note that start once the current one is done
+    pending: VecDeque<Job>,
+}
+
+#[derive(Default)]
+struct Queue {
+    jobs: BinaryHeap<Job>,
+    next_order: u64,
+    voices: HashMap<Voice, VoiceState>,
+
+    /// The `order` of the job that has set up the modifiers and octave for
+    /// its key and not pressed it yet, or that is holding state modifiers
+    /// it pressed itself.  Another note changing them in the meantime would
+    /// put the key in the wrong octave.
+    modifier_owner: Option<u64>,
+
+    /// Jobs that want to change the modifiers while another job owns them
+    blocked: Vec<Job>,
+}
+
+impl Queue {
+    /// Remove a job from the heap or the blocked jobs, wherever it is.
+    fn take(&mut self, order: u64) -> Option<Job> {
+        if let Some(pos) = self.blocked.iter().position(|job| job.order == order) {
+            return Some(self.blocked.remove(pos));
+        }
+        let mut jobs = mem::take(&mut self.jobs).into_vec();
+        let job = jobs
+            .iter()
+            .position(|job| job.order == order)
+            .map(|pos| jobs.swap_remove(pos));
+        self.jobs = jobs.into();
+        job
+    }
+
+    /// Whether a job can run its next event without disturbing the
+    /// modifiers that another job is relying on
+    fn may_run(&self, job: &Job, state_modifiers: &[KbdKey]) -> bool {
+        !job.wants_modifiers(state_modifiers)
+            || self.modifier_owner.is_none_or(|owner| owner == job.order)
+    }
+
+    /// Let other jobs change the modifiers again, if the job `order` owned them
+    fn release_modifiers(&mut self, order: u64) {
+        if self.modifier_owner == Some(order) {
+            self.modifier_owner = None;
+            let blocked = mem::take(&mut self.blocked);
+            self.jobs.extend(blocked);
+        }
+    }
+
+    /// Called when the current job for a voice has finished or been
+    /// cancelled, to start the next one waiting for that note.
+    fn finished(&mut self, voice: Voice) {
+        let next = match self.voices.get_mut(&voice) {
+            Some(state) => state.pending.pop_front(),
+            None => return,
+        };
+        match next {
+            Some(job) => {
+                self.voices.get_mut(&voice).unwrap().current = job.order;
+                self.jobs.push(job);
+            }
+            None => {
+                self.voices.remove(&voice);
+            }
+        }
+    }
+}
+
+/// Runs note sequences on a dedicated output thread.  MIDI callbacks only
+/// queue work, so a long sequence such as a macro with several delays never
+/// holds up notes from the same or other devices.
+pub struct Scheduler {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    keygen: Arc<Mutex<KeyGen>>,
+}
+
+impl Scheduler {
+    /// Start the output thread, which sends keys through `keygen`.
+    pub fn new(keygen: Arc<Mutex<KeyGen>>) -> Scheduler {
+        let queue = Arc::new((Mutex::new(Queue::default()), Condvar::new()));
+        let queue_thr = queue.clone();
+        let keygen_thr = keygen.clone();
+        thread::Builder::new()
+            .name("output".to_owned())
+            .spawn(move || Scheduler::worker(&queue_thr, &keygen_thr))
+            .expect("unable to start output thread");
+        Scheduler { queue, keygen }
+    }
+
+    /// Run one of a mapping's sequences for a note, starting at `due`.
+    /// Delays within the sequence are measured from that time.
+    ///
+    /// If a NoteOn sequence is still running for the same note, the release
+    /// policy of its mapping decides whether it is left to finish, cut short,
+    /// or cancelled before this sequence starts.
+    pub fn schedule(&self, due: Instant, mapping: Arc<NoteMapping>, phase: Phase, voice: Voice) {
+        let (lock, cvar) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        let order = queue.next_order;
+        queue.next_order += 1;
+        let job = Job {
+            due,
+            order,
+            mapping,
+            phase,
+            voice,
+            pos: 0,
+            truncate: false,
+            holds_modifiers: false,
+            pressed: vec![],
+        };
+
+        let current = match queue.voices.get(&voice) {
+            Some(state) => state.current,
+            None => {
+                queue.voices.insert(
+                    voice,
+                    VoiceState {
+                        current: order,
+                        pending: VecDeque::new(),
+                    },
+                );
+                queue.jobs.push(job);
+                cvar.notify_all();
+                return;
+            }
+        };
+
+        queue.voices.get_mut(&voice).unwrap().pending.push_back(job);
+
+        if let Some(mut running) = queue.take(current) {
+            let policy = match running.phase {
+                Phase::On => running.mapping.release,
+                // Release sequences always run to completion
+                Phase::Off => ReleasePolicy::Finish,
+            };
+            match policy {
+                ReleasePolicy::Finish => queue.jobs.push(running),
+                ReleasePolicy::Truncate => {
+                    running.truncate = true;
+                    running.due = running.due.min(Instant::now());
+                    queue.jobs.push(running);
+                }
+                // Let go of the keys that the abandoned sequence is holding
+                ReleasePolicy::Cancel => {
+                    let mut keygen = self.keygen.lock().unwrap();
+                    for key in running.pressed.iter().rev() {
+                        keygen.key_up(key);
+                    }
+                    drop(keygen);
+                    queue.release_modifiers(running.order);
+                    queue.finished(voice);
+                }
+            }
+        }
+        cvar.notify_all();
+    }
+
+    /// Drop every queued and running sequence.  A step that is already
+    /// running is allowed to complete first.
+    pub fn cancel_all(&self) {
+        let (lock, cvar) = &*self.queue;
+        let mut queue = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        queue.jobs.clear();
+        queue.blocked.clear();
+        queue.voices.clear();
+        queue.modifier_owner = None;
+        cvar.notify_all();
+    }
+
+    /// Drop every queued and running sequence for a MIDI channel.
+    pub fn cancel_channel(&self, channel: u8) {
+        let (lock, cvar) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        queue.jobs.retain(|job| job.voice.channel != channel);
+        queue.blocked.retain(|job| job.voice.channel != channel);
+        queue.voices.retain(|voice, _| voice.channel != channel);
+        if let Some(owner) = queue.modifier_owner {
+            if !queue.jobs.iter().any(|job| job.order == owner) {
+                queue.release_modifiers(owner);
+            }
+        }
+        cvar.notify_all();
+    }
+
+    /// Block until every queued sequence has finished.
+    pub fn wait_idle(&self) {
+        let (lock, cvar) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        while !queue.jobs.is_empty() {
+            queue = cvar.wait(queue).unwrap();
+        }
+    }
+
+    fn worker(queue: &(Mutex<Queue>, Condvar), keygen: &Mutex<KeyGen>) {
+        let (lock, cvar) = queue;
+        let mut queue = lock.lock().unwrap();
+        loop {
+            let now = Instant::now();
+
+            // Let go of any keys that have been held for too long, in case
+            // their note off went missing
+            let mut keygen_guard = keygen.lock().unwrap();
+            for (key, held) in keygen_guard.release_expired(now) {
+                println!(
+                    "Released {} after it was held for {} ms",
+                    key,
+                    held.as_millis()
+                );
+            }
+            let next_deadline = keygen_guard.next_deadline();
+            drop(keygen_guard);
+
+            let wait = match queue.jobs.peek() {
+                Some(job) if job.due <= now => None,
+                Some(job) => Some(job.due - now),
+                None => Some(Duration::from_secs(1)),
+            };
+            let wait = match (wait, next_deadline) {
+                (Some(wait), Some(deadline)) => {
+                    Some(wait.min(deadline.saturating_duration_since(now)))
+                }
+                (wait, _) => wait,
+            };
+            if let Some(wait) = wait {
+                queue = cvar.wait_timeout(queue, wait).unwrap().0;
+                continue;
+            }
+
+            // The queue stays locked while the job runs, so that it can't be
+            // cancelled halfway through a step.  Steps never sleep.
+            let mut job = queue.jobs.pop().unwrap();
+            let mut keygen_guard = keygen.lock().unwrap();
+            if !queue.may_run(&job, keygen_guard.state_modifiers()) {
+                queue.blocked.push(job);
+                continue;
+            }
+            let finished = Scheduler::run_job(&mut job, &mut keygen_guard);
+            let owns_modifiers = job.owns_modifiers(keygen_guard.state_modifiers());
+            drop(keygen_guard);
+            if owns_modifiers && !finished {
+                queue.modifier_owner = Some(job.order);
+            } else {
+                queue.release_modifiers(job.order);
+            }
+            if finished {
+                queue.finished(job.voice);
+            } else {
+                queue.jobs.push(job);
+            }
+            cvar.notify_all();
+        }
+    }
+
+    /// Run events from a job until it either finishes or needs to wait.
+    /// Returns `true` if the job has finished, otherwise its `due` time has
+    /// been moved to when it should continue.
+    ///
+    /// The worker only lets one job at a time run a NoteMod or Octave, and
+    /// keeps it to that job until its KeyDown, so that sequences for other
+    /// notes can't change the modifiers or octave under it.  A job that
+    /// presses a state modifier itself keeps them until it releases it.
+    fn run_job(job: &mut Job, keygen: &mut KeyGen) -> bool {
+        if job.pos == 0 {
+            keygen.backend().sequence_start(&job.mapping, job.phase);
+        }
+        while job.pos < job.events().len() {
+            let event = job.events()[job.pos].clone();
+            job.pos += 1;
+            if let Event::NoteMod(_) | Event::Octave(_) = event {
+                job.holds_modifiers = true;
+            }
+            let delay = match event {
+                Event::Delay(msecs) => msecs,
+                Event::Settle(settle) => keygen.settle_time(settle, &job.mapping.timing),
+                Event::KeyDown(ref k) => {
+                    keygen.key_down(k, job.voice.channel, job.mapping.hold);
+                    if !keygen.state_modifiers().contains(k) {
+                        job.holds_modifiers = false;
+                    }
+                    job.pressed.push(k.clone());
+                    0
+                }
+                Event::KeyUp(ref k) => {
+                    keygen.key_up(k);
+                    job.pressed.retain(|held| held != k);
+                    0
+                }
+
+                // Tap the octave keys one at a time, coming back to this
+                // event after each delay until the game is in the right octave.
+                Event::Octave(target) => match keygen.octave_step(target, job.voice.channel) {
+                    OctaveStep::Done => 0,
+                    OctaveStep::Pressed => {
+                        job.pos -= 1;
+                        keygen.settle_time(Settle::Key, &job.mapping.timing)
+                    }
+                    OctaveStep::Released(settle_ms) => {
+                        job.pos -= 1;
+                        settle_ms
+                            .unwrap_or_else(|| keygen.settle_time(Settle::Key, &job.mapping.timing))
+                    }
+                },
+
+                // For NoteMod, which goes at the top of a note, see if we need to change
+                // the current set of modifiers.  If so, pause a short while.
+                // This enables fast switching between notes in the same octave, where no
+                // keychange is required.
+                Event::NoteMod(ref wanted) => {
+                    let mut changes = 0;
+                    // Release the unwanted modifiers first, so that the
+                    // game never sees a mix of the old and new sets
+                    for idx in 0..keygen.state_modifiers().len() {
+                        let key_mod = keygen.state_modifiers()[idx].clone();
+                        if !wanted.contains(&key_mod) && keygen.key_up(&key_mod) {
+                            changes += 1;
+                        }
+                    }
+                    for key_mod in wanted {
+                        if keygen.key_down(key_mod, job.voice.channel, job.mapping.hold) {
+                            changes += 1;
+                        }
+                    }
+                    if changes > 0 {
+                        keygen.backend().modifier_change(wanted);
+                        keygen.settle_time(Settle::Octave, &job.mapping.timing)
+                    } else {
+                        0
+                    }
+                }
+            };
+
+            if delay > 0 && !job.truncate {
+                keygen.backend().delay(delay);
+                job.due += Duration::from_millis(delay);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Converts the microsecond timestamps of one MIDI connection into
+/// `Instant`s, so that queued work can be timed against when a message was
+/// received rather than when the callback got around to it.
+#[derive(Default)]
+pub struct Clock {
+    base: Option<Instant>,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock::default()
+    }
+
+    pub fn instant(&mut self, timestamp_us: u64) -> Instant {
+        let now = Instant::now();
+        let offset = Duration::from_micros(timestamp_us);
+        let anchor = now.checked_sub(offset).unwrap_or(now);
+        let instant = *self.base.get_or_insert(anchor) + offset;
+
+        // Never schedule anything in the future because of clock drift
+        if instant > now {
+            self.base = Some(anchor);
+            now
+        } else {
+            instant
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notemappings::{OctaveKeys, Timing};
+    use crate::output::RecordingBackend;
+
+    /// A scheduler that records its keys, with short settle times so the
+    /// tests run quickly
+    fn recording_scheduler(octave_keys: Option<OctaveKeys>) -> (Scheduler, RecordingBackend) {
+        let backend = RecordingBackend::new();
+        let mut keygen = KeyGen::with_backend(Box::new(backend.clone()));
+        let mut timing = Timing::new();
+        for setting in &["mod=5", "key=5", "sys=5", "octave=5"] {
+            timing.parse_setting(setting).unwrap();
+        }
+        keygen.set_timing(timing);
+        keygen.set_octave_keys(octave_keys);
+        (Scheduler::new(Arc::new(Mutex::new(keygen))), backend)
+    }
+
+    /// Each key event as "+key" or "-key", leaving out releases of keys that
+    /// weren't held, which don't change anything
+    fn key_log(backend: &RecordingBackend) -> Vec<String> {
+        let mut held = vec![];
+        let mut log = vec![];
+        for stroke in backend.log() {
+            if stroke.down {
+                held.push(stroke.key.clone());
+                log.push(format!("+{}", stroke.key));
+            } else if held.contains(&stroke.key) {
+                held.retain(|key| *key != stroke.key);
+                log.push(format!("-{}", stroke.key));
+            }
+        }
+        log
+    }
+
+    fn note(index: u8) -> MidiNote {
+        MidiNote::new(index).unwrap()
+    }
+
+    fn key_mapping(index: u8, key: char, modifiers: &[KbdKey]) -> Arc<NoteMapping> {
+        let mut mapping = NoteMapping::new(note(index), 0, None);
+        mapping.on = NoteMapping::down_event(key, modifiers);
+        mapping.off = NoteMapping::up_event(key);
+        Arc::new(mapping)
+    }
+
+    fn octave_mapping(index: u8, key: char, octave: i8) -> Arc<NoteMapping> {
+        let mut mapping = NoteMapping::new(note(index), 0, None);
+        mapping.on = vec![
+            Event::NoteMod(vec![]),
+            Event::Octave(octave),
+            Event::KeyDown(KbdKey::Layout(key)),
+        ];
+        mapping.off = NoteMapping::up_event(key);
+        Arc::new(mapping)
+    }
+
+    /// Run a mapping's sequence as if `index` had been played on channel 0
+    fn play(scheduler: &Scheduler, index: u8, mapping: &Arc<NoteMapping>, phase: Phase) {
+        let voice = Voice::new(None, 0, note(index));
+        scheduler.schedule(Instant::now(), mapping.clone(), phase, voice);
+    }
+
+    #[test]
+    fn note_with_modifiers() {
+        let (scheduler, backend) = recording_scheduler(None);
+        let mapping = key_mapping(48, 'q', &[KbdKey::Control]);
+        play(&scheduler, 48, &mapping, Phase::On);
+        play(&scheduler, 48, &mapping, Phase::Off);
+        scheduler.wait_idle();
+        assert_eq!(key_log(&backend), ["+ctrl", "+q", "-q"]);
+    }
+
+    #[test]
+    fn settle_waits_between_keys() {
+        let (scheduler, backend) = recording_scheduler(None);
+        let mut mapping = NoteMapping::new(note(40), 0, None);
+        mapping.timing.parse_setting("sys=50").unwrap();
+        mapping.on = vec![
+            Event::KeyDown(KbdKey::Escape),
+            Event::Settle(Settle::System),
+            Event::KeyUp(KbdKey::Escape),
+        ];
+        play(&scheduler, 40, &Arc::new(mapping), Phase::On);
+        scheduler.wait_idle();
+
+        let log = backend.log();
+        assert_eq!(key_log(&backend), ["+escape", "-escape"]);
+        assert!(log[1].time - log[0].time >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn octave_keys_are_tapped_before_the_key() {
+        let octave_keys = OctaveKeys::new(KbdKey::Layout('x'), KbdKey::Layout('z'));
+        let (scheduler, backend) = recording_scheduler(Some(octave_keys));
+        play(&scheduler, 64, &octave_mapping(64, 'e', 1), Phase::On);
+        play(&scheduler, 65, &octave_mapping(65, 'r', -1), Phase::On);
+        scheduler.wait_idle();
+        assert_eq!(
+            key_log(&backend),
+            ["+x", "-x", "+e", "+z", "-z", "+z", "-z", "+r"]
+        );
+    }
+
+    #[test]
+    fn overlapping_notes_keep_their_own_modifiers() {
+        let (scheduler, backend) = recording_scheduler(None);
+        play(
+            &scheduler,
+            48,
+            &key_mapping(48, 'q', &[KbdKey::Control]),
+            Phase::On,
+        );
+        play(
+            &scheduler,
+            74,
+            &key_mapping(74, 'w', &[KbdKey::Shift]),
+            Phase::On,
+        );
+        scheduler.wait_idle();
+        assert_eq!(key_log(&backend), ["+ctrl", "+q", "-ctrl", "+shift", "+w"]);
+    }
+
+    #[test]
+    fn cancel_releases_held_keys() {
+        let (scheduler, backend) = recording_scheduler(None);
+        let mut mapping = NoteMapping::new(note(60), 0, None);
+        mapping.release = ReleasePolicy::Cancel;
+        mapping.on = vec![
+            Event::KeyDown(KbdKey::Control),
+            Event::KeyDown(KbdKey::Layout('a')),
+            Event::Delay(500),
+            Event::KeyUp(KbdKey::Layout('a')),
+            Event::KeyUp(KbdKey::Control),
+            Event::KeyDown(KbdKey::Layout('b')),
+        ];
+        let mapping = Arc::new(mapping);
+        play(&scheduler, 60, &mapping, Phase::On);
+        thread::sleep(Duration::from_millis(50));
+        play(&scheduler, 60, &mapping, Phase::Off);
+        scheduler.wait_idle();
+
+        let log = backend.log();
+        assert_eq!(key_log(&backend), ["+ctrl", "+a", "-a", "-ctrl"]);
+        assert!(log[log.len() - 1].time - log[0].time < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn pressed_modifiers_are_kept_until_released() {
+        let (scheduler, backend) = recording_scheduler(None);
+        let mut shortcut = NoteMapping::new(note(40), 0, None);
+        shortcut.on = vec![
+            Event::KeyDown(KbdKey::Control),
+            Event::KeyDown(KbdKey::Shift),
+            Event::Settle(Settle::Modifier),
+            Event::KeyDown(KbdKey::Layout('z')),
+            Event::Settle(Settle::Key),
+            Event::KeyUp(KbdKey::Layout('z')),
+            Event::KeyUp(KbdKey::Shift),
+            Event::KeyUp(KbdKey::Control),
+        ];
+        play(&scheduler, 40, &Arc::new(shortcut), Phase::On);
+        play(
+            &scheduler,
+            49,
+            &key_mapping(49, '2', &[KbdKey::Control]),
+            Phase::On,
+        );
+        scheduler.wait_idle();
+        assert_eq!(
+            key_log(&backend),
+            ["+ctrl", "+shift", "+z", "-z", "-shift", "-ctrl", "+ctrl", "+2"]
+        );
+    }
+}
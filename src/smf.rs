@@ -0,0 +1,497 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use crate::midi::MidiMessage;
+
+/// The tempo a file plays at until it says otherwise, in microseconds per quarter note
+const DEFAULT_TEMPO_US: u64 = 500_000;
+
+#[derive(Debug)]
+pub enum SmfError {
+    Io(io::Error),
+    /// The file isn't a Standard MIDI File, or is damaged
+    Invalid(&'static str),
+    /// The file is a type that we can't play (i.e. type 2)
+    UnsupportedFormat(u16),
+}
+
+impl fmt::Display for SmfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmfError::Io(e) => write!(f, "unable to read MIDI file: {}", e),
+            SmfError::Invalid(reason) => write!(f, "invalid MIDI file: {}", reason),
+            SmfError::UnsupportedFormat(format) => {
+                write!(f, "unsupported MIDI file format {}", format)
+            }
+        }
+    }
+}
+
+impl Error for SmfError {}
+
+impl From<io::Error> for SmfError {
+    fn from(e: io::Error) -> SmfError {
+        SmfError::Io(e)
+    }
+}
+
+/// A channel message from a MIDI file, placed on an absolute timeline.
+#[derive(Debug)]
+pub struct SmfEvent {
+    /// Time from the start of the song, in microseconds
+    pub time_us: u64,
+
+    /// Index of the track this event came from
+    pub track: usize,
+
+    pub message: MidiMessage,
+}
+
+/// A Standard MIDI File (type 0 or 1) with all tracks merged into one
+/// timeline, and all tick times converted to real time using the tempo map.
+#[derive(Debug)]
+pub struct Smf {
+    pub format: u16,
+    pub track_count: usize,
+    pub events: Vec<SmfEvent>,
+}
+
+/// The time base from the header chunk
+#[derive(Clone, Copy)]
+enum Division {
+    /// Ticks per quarter note, scaled by the tempo map
+    Metrical(u64),
+    /// A fixed number of microseconds per tick, for SMPTE time bases,
+    /// stored as (numerator, denominator)
+    Timecode(u64, u64),
+}
+
+enum TrackEvent {
+    Tempo(u64),
+    Message(MidiMessage),
+}
+
+/// A cursor over a chunk of file data
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], SmfError> {
+        if self.data.len() - self.pos < count {
+            return Err(SmfError::Invalid("unexpected end of data"));
+        }
+        let bytes = &self.data[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SmfError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn peek(&self) -> Result<u8, SmfError> {
+        self.data
+            .get(self.pos)
+            .cloned()
+            .ok_or(SmfError::Invalid("unexpected end of data"))
+    }
+
+    fn u16(&mut self) -> Result<u16, SmfError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from(b[0]) << 8 | u16::from(b[1]))
+    }
+
+    fn u32(&mut self) -> Result<u32, SmfError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from(b[0]) << 24 | u32::from(b[1]) << 16 | u32::from(b[2]) << 8 | u32::from(b[3]))
+    }
+
+    /// Read a variable-length quantity, which is at most four bytes long
+    fn vlq(&mut self) -> Result<u32, SmfError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = value << 7 | u32::from(byte & 0x7f);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(SmfError::Invalid("variable-length value is too long"))
+    }
+
+    /// Read a chunk header, returning the chunk type and its contents
+    fn chunk(&mut self) -> Result<(&'a [u8], &'a [u8]), SmfError> {
+        let kind = self.bytes(4)?;
+        let len = self.u32()? as usize;
+        Ok((kind, self.bytes(len)?))
+    }
+}
+
+impl Smf {
+    pub fn open(path: &str) -> Result<Smf, SmfError> {
+        Smf::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Smf, SmfError> {
+        let mut file = Reader::new(data);
+        let (kind, header) = file.chunk()?;
+        if kind != b"MThd" || header.len() < 6 {
+            return Err(SmfError::Invalid("missing MThd header"));
+        }
+        let mut header = Reader::new(header);
+        let format = header.u16()?;
+        let track_count = header.u16()? as usize;
+        let division = header.u16()?;
+
+        if format > 1 {
+            return Err(SmfError::UnsupportedFormat(format));
+        }
+
+        let division = if division & 0x8000 == 0 {
+            if division == 0 {
+                return Err(SmfError::Invalid("zero ticks per quarter note"));
+            }
+            Division::Metrical(u64::from(division))
+        } else {
+            let ticks_per_frame = u64::from(division & 0xff);
+            // 29 is 29.97 fps drop-frame
+            let (fps_num, fps_den) = match (division >> 8) as u8 as i8 {
+                -24 => (24, 1),
+                -25 => (25, 1),
+                -29 => (2997, 100),
+                -30 => (30, 1),
+                _ => return Err(SmfError::Invalid("unknown SMPTE frame rate")),
+            };
+            if ticks_per_frame == 0 {
+                return Err(SmfError::Invalid("zero ticks per frame"));
+            }
+            Division::Timecode(1_000_000 * fps_den, fps_num * ticks_per_frame)
+        };
+
+        // Gather (tick, track, event) for every track, skipping unknown chunks.
+        let mut tick_events = vec![];
+        let mut track = 0;
+        while !file.done() && track < track_count {
+            let (kind, contents) = file.chunk()?;
+            if kind != b"MTrk" {
+                continue;
+            }
+            Smf::parse_track(contents, track, &mut tick_events)?;
+            track += 1;
+        }
+
+        // The sort is stable, so simultaneous events stay in file order.
+        tick_events.sort_by_key(|&(tick, track, _)| (tick, track));
+
+        // Walk the merged timeline, converting ticks into microseconds.
+        let mut events = vec![];
+        let mut tempo = DEFAULT_TEMPO_US;
+        let mut last_tick = 0;
+        let mut last_us = 0;
+        for (tick, track, event) in tick_events {
+            let delta = tick - last_tick;
+            let delta_us = match division {
+                Division::Metrical(tpqn) => delta * tempo / tpqn,
+                Division::Timecode(num, den) => delta * num / den,
+            };
+            last_tick = tick;
+            last_us += delta_us;
+            match event {
+                TrackEvent::Tempo(new_tempo) => tempo = new_tempo,
+                TrackEvent::Message(message) => events.push(SmfEvent {
+                    time_us: last_us,
+                    track,
+                    message,
+                }),
+            }
+        }
+
+        Ok(Smf {
+            format,
+            track_count: track,
+            events,
+        })
+    }
+
+    fn parse_track(
+        data: &[u8],
+        track: usize,
+        events: &mut Vec<(u64, usize, TrackEvent)>,
+    ) -> Result<(), SmfError> {
+        let mut reader = Reader::new(data);
+        let mut tick: u64 = 0;
+        let mut running_status = None;
+
+        while !reader.done() {
+            tick += u64::from(reader.vlq()?);
+
+            let status = if reader.peek()? & 0x80 != 0 {
+                reader.u8()?
+            } else {
+                running_status.ok_or(SmfError::Invalid("data byte without a status byte"))?
+            };
+
+            match status {
+                0xff => {
+                    running_status = None;
+                    let kind = reader.u8()?;
+                    let len = reader.vlq()? as usize;
+                    let data = reader.bytes(len)?;
+                    match kind {
+                        // Set Tempo
+                        0x51 if len == 3 => {
+                            let tempo = u64::from(data[0]) << 16
+                                | u64::from(data[1]) << 8
+                                | u64::from(data[2]);
+                            events.push((tick, track, TrackEvent::Tempo(tempo)));
+                        }
+                        // End of Track
+                        0x2f => break,
+                        _ => (),
+                    }
+                }
+                0xf0 | 0xf7 => {
+                    running_status = None;
+                    let len = reader.vlq()? as usize;
+                    reader.bytes(len)?;
+                }
+                0x80..=0xef => {
+                    running_status = Some(status);
+                    let len = match status & 0xf0 {
+                        0xc0 | 0xd0 => 1,
+                        _ => 2,
+                    };
+                    let mut raw = [status, 0, 0];
+                    raw[1..=len].copy_from_slice(reader.bytes(len)?);
+                    if let Ok(message) = MidiMessage::new(&raw[..=len]) {
+                        events.push((tick, track, TrackEvent::Message(message)));
+                    }
+                }
+                _ => return Err(SmfError::Invalid("unexpected status byte in track")),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Ticks per quarter note used when writing files
+const WRITE_TICKS_PER_QUARTER: u64 = 480;
+
+/// A track to be written out by `write_file`
+pub struct SmfTrack {
+    pub name: String,
+
+    /// (time in microseconds, raw message) pairs, in time order
+    pub events: Vec<(u64, Vec<u8>)>,
+}
+
+fn push_vlq(out: &mut Vec<u8>, value: u64) {
+    let mut value = value.min(0x0fff_ffff);
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    out.extend(bytes);
+}
+
+fn push_chunk(out: &mut Vec<u8>, kind: &[u8], contents: &[u8]) {
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&(contents.len() as u32).to_be_bytes());
+    out.extend_from_slice(contents);
+}
+
+/// Encode the track contents for a set of events.  Only messages that a MIDI
+/// file can carry (channel messages and complete SysEx blocks) are written.
+fn encode_track(name: &str, tempo: bool, events: &[(u64, Vec<u8>)]) -> Vec<u8> {
+    let mut out = vec![];
+    if !name.is_empty() {
+        out.extend_from_slice(&[0x00, 0xff, 0x03]);
+        push_vlq(&mut out, name.len() as u64);
+        out.extend_from_slice(name.as_bytes());
+    }
+    if tempo {
+        out.extend_from_slice(&[0x00, 0xff, 0x51, 0x03]);
+        out.extend_from_slice(&DEFAULT_TEMPO_US.to_be_bytes()[5..]);
+    }
+
+    let mut last_tick = 0;
+    for (time_us, raw) in events {
+        let status = match raw.first() {
+            Some(status) => *status,
+            None => continue,
+        };
+        if !(0x80..0xf0).contains(&status) && status != 0xf0 {
+            continue;
+        }
+
+        // Events are sorted, but never let a delta go negative
+        let tick = time_us * WRITE_TICKS_PER_QUARTER / DEFAULT_TEMPO_US;
+        push_vlq(&mut out, tick.saturating_sub(last_tick));
+        last_tick = tick;
+        if status == 0xf0 {
+            out.push(0xf0);
+            push_vlq(&mut out, raw.len() as u64 - 1);
+            out.extend_from_slice(&raw[1..]);
+        } else {
+            out.extend_from_slice(raw);
+        }
+    }
+
+    out.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+    out
+}
+
+/// Write a Standard MIDI File.  A type 0 file gets every track merged into
+/// one, and a type 1 file gets a tempo track followed by one track each.
+pub fn write_file(path: &str, format: u16, tracks: &[SmfTrack]) -> Result<(), SmfError> {
+    let mut track_data = vec![];
+    match format {
+        0 => {
+            let mut events: Vec<(u64, Vec<u8>)> = tracks
+                .iter()
+                .flat_map(|track| track.events.iter().cloned())
+                .collect();
+            events.sort_by_key(|&(time_us, _)| time_us);
+            track_data.push(encode_track("", true, &events));
+        }
+        1 => {
+            track_data.push(encode_track("", true, &[]));
+            for track in tracks {
+                let mut events = track.events.clone();
+                events.sort_by_key(|&(time_us, _)| time_us);
+                track_data.push(encode_track(&track.name, false, &events));
+            }
+        }
+        _ => return Err(SmfError::UnsupportedFormat(format)),
+    }
+
+    let mut out = vec![];
+    let mut header = vec![];
+    header.extend_from_slice(&format.to_be_bytes());
+    header.extend_from_slice(&(track_data.len() as u16).to_be_bytes());
+    header.extend_from_slice(&(WRITE_TICKS_PER_QUARTER as u16).to_be_bytes());
+    push_chunk(&mut out, b"MThd", &header);
+    for data in &track_data {
+        push_chunk(&mut out, b"MTrk", data);
+    }
+    fs::write(path, out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::MidiEvent;
+
+    fn summary(smf: &Smf) -> Vec<(u64, usize, MidiEvent, u8, u8)> {
+        smf.events
+            .iter()
+            .map(|event| {
+                let msg = &event.message;
+                let note = msg.note().map(|note| note.index()).unwrap_or(0);
+                (
+                    event.time_us,
+                    event.track,
+                    *msg.event(),
+                    note,
+                    msg.velocity().unwrap_or(0),
+                )
+            })
+            .collect()
+    }
+
+    fn track(events: &[(u64, u8, u8)]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut last_tick = 0;
+        for &(tick, status, note) in events {
+            push_vlq(&mut out, tick - last_tick);
+            last_tick = tick;
+            out.extend_from_slice(&[status, note, 0x40]);
+        }
+        out.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+        out
+    }
+
+    #[test]
+    fn written_files_read_back() {
+        // Whole quarter notes land on exact ticks, so nothing gets rounded
+        let tracks = vec![
+            SmfTrack {
+                name: "Piano".to_owned(),
+                events: vec![
+                    (0, vec![0x90, 60, 100]),
+                    (500_000, vec![0x80, 60, 0]),
+                    (1_000_000, vec![0x90, 62, 90]),
+                ],
+            },
+            SmfTrack {
+                name: "Drums".to_owned(),
+                events: vec![(1_500_000, vec![0x99, 36, 127]), (250_000, vec![0xc9, 5])],
+            },
+        ];
+        let path = std::env::temp_dir().join(format!("miditran-smf-{}.mid", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        write_file(path, 1, &tracks).unwrap();
+        let smf = Smf::open(path).unwrap();
+        assert_eq!(smf.format, 1);
+        assert_eq!(smf.track_count, 3);
+        assert_eq!(
+            summary(&smf),
+            vec![
+                (0, 1, MidiEvent::NoteOn, 60, 100),
+                (250_000, 2, MidiEvent::ProgramChange, 0, 0),
+                (500_000, 1, MidiEvent::NoteOff, 60, 0),
+                (1_000_000, 1, MidiEvent::NoteOn, 62, 90),
+                (1_500_000, 2, MidiEvent::NoteOn, 36, 127),
+            ]
+        );
+
+        write_file(path, 0, &tracks).unwrap();
+        let smf = Smf::open(path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(smf.format, 0);
+        assert_eq!(smf.track_count, 1);
+        let times: Vec<u64> = smf.events.iter().map(|event| event.time_us).collect();
+        assert_eq!(times, vec![0, 250_000, 500_000, 1_000_000, 1_500_000]);
+    }
+
+    #[test]
+    fn tempo_changes_apply_to_every_track() {
+        // 96 ticks per quarter; the tempo doubles after the first quarter
+        let mut tempo_track = vec![0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20];
+        tempo_track.extend_from_slice(&[0x60, 0xff, 0x51, 0x03, 0x03, 0xd0, 0x90]);
+        tempo_track.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+        let notes = track(&[
+            (0, 0x90, 60),
+            (96, 0x90, 62),
+            (192, 0x90, 64),
+            (384, 0x80, 64),
+        ]);
+
+        let mut data = vec![];
+        push_chunk(&mut data, b"MThd", &[0, 1, 0, 2, 0, 96]);
+        push_chunk(&mut data, b"MTrk", &tempo_track);
+        push_chunk(&mut data, b"MTrk", &notes);
+
+        let smf = Smf::parse(&data).unwrap();
+        let times: Vec<u64> = smf.events.iter().map(|event| event.time_us).collect();
+        assert_eq!(times, vec![0, 500_000, 750_000, 1_250_000]);
+        assert!(smf.events.iter().all(|event| event.track == 1));
+    }
+}